@@ -62,13 +62,13 @@ impl Bouncer {
         let mut time_until_next_deadline = self.millis_budget - millis_in;
         if let Some(grace_period) = self.hot_deadline_proximity {
             if time_until_next_deadline < grace_period {
-                time_until_next_deadline = time_until_next_deadline + self.millis_budget;
+                time_until_next_deadline += self.millis_budget;
             }
         }
 
         let next_deadline: Instant = now
             .checked_add(Duration::from_millis(
-                time_until_next_deadline.min(u64::max_value() as u128) as u64,
+                time_until_next_deadline.min(u64::MAX as u128) as u64,
             ))
             .expect("We have reached the end of time.");
 
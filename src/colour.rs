@@ -0,0 +1,270 @@
+use lazy_static::lazy_static;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use syntect::highlighting::{Color, Style};
+
+/// How many colours the terminal can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColourDepth {
+    TrueColour,
+    Ansi256,
+    Ansi16,
+}
+
+lazy_static! {
+    static ref DEPTH: ColourDepth = detect();
+}
+
+/// The colour depth of the terminal the editor is running in.
+pub fn depth() -> ColourDepth {
+    *DEPTH
+}
+
+fn detect() -> ColourDepth {
+    let term = env::var("TERM").ok();
+    let depth = detect_from(
+        env::var("COLORTERM").ok().as_deref(),
+        term.as_deref(),
+        term.as_deref().and_then(terminfo_colours),
+    );
+    log::debug!("Using colour depth {:?}", depth);
+    depth
+}
+
+/// Works out the colour depth from `$COLORTERM`, `$TERM` and the number of
+/// colours terminfo gives for it.
+fn detect_from(
+    colorterm: Option<&str>,
+    term: Option<&str>,
+    terminfo_colours: Option<u32>,
+) -> ColourDepth {
+    if matches!(colorterm, Some("truecolor" | "24bit")) {
+        return ColourDepth::TrueColour;
+    }
+    match terminfo_colours {
+        Some(n) if n >= 1 << 24 => ColourDepth::TrueColour,
+        Some(n) if n >= 256 => ColourDepth::Ansi256,
+        Some(_) => ColourDepth::Ansi16,
+        None => match term {
+            Some(t) if t.contains("truecolor") || t.contains("direct") => ColourDepth::TrueColour,
+            Some(t) if t.contains("256color") => ColourDepth::Ansi256,
+            _ => ColourDepth::Ansi16,
+        },
+    }
+}
+
+/// Where `max_colors` is among terminfo's numeric capabilities
+const MAX_COLORS: usize = 13;
+
+/// The number of colours the terminfo entry for `term` says it has.
+fn terminfo_colours(term: &str) -> Option<u32> {
+    let first = term.chars().next()?;
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    dirs.extend(env::var_os("TERMINFO").map(PathBuf::from));
+    dirs.extend(env::var_os("HOME").map(|home| PathBuf::from(home).join(".terminfo")));
+    if let Some(list) = env::var_os("TERMINFO_DIRS") {
+        dirs.extend(env::split_paths(&list));
+    }
+    dirs.extend(
+        ["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo"]
+            .iter()
+            .map(PathBuf::from),
+    );
+
+    // entries are filed under their first letter, or its hex code on macOS
+    dirs.iter()
+        .flat_map(|dir| {
+            [first.to_string(), format!("{:x}", first as u32)].map(|sub| dir.join(sub).join(term))
+        })
+        .find_map(|path| fs::read(path).ok())
+        .and_then(|entry| max_colours(&entry))
+}
+
+/// Reads `max_colors` from a compiled terminfo entry.
+fn max_colours(entry: &[u8]) -> Option<u32> {
+    let short = |i: usize| {
+        entry
+            .get(2 * i..2 * i + 2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+    };
+    // the extended format has 32-bit numbers
+    let number_size = match short(0)? {
+        0o432 => 2,
+        0o1036 => 4,
+        _ => return None,
+    };
+    let names_size = short(1)? as usize;
+    let bools = short(2)? as usize;
+    let numbers = short(3)? as usize;
+    if numbers <= MAX_COLORS {
+        return None;
+    }
+
+    // numbers start on an even byte after the header, names and booleans
+    let start = (12 + names_size + bools + 1) & !1;
+    let at = start + MAX_COLORS * number_size;
+    let bytes = entry.get(at..at + number_size)?;
+    let n = match *bytes {
+        [a, b] => i16::from_le_bytes([a, b]) as i32,
+        [a, b, c, d] => i32::from_le_bytes([a, b, c, d]),
+        _ => return None,
+    };
+    (n > 0).then_some(n as u32)
+}
+
+/// xterm's default colours for the 16 basic ANSI colours
+const ANSI_16: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// The levels of each channel in the 6x6x6 colour cube of the 256-colour
+/// palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// The closest colour in the 256-colour palette, from its colour cube or its
+/// grey ramp.
+pub fn to_256(c: Color) -> u8 {
+    let rgb = (c.r, c.g, c.b);
+    let level = |v: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|i| (CUBE_LEVELS[*i] as i32 - v as i32).abs())
+            .unwrap_or(0)
+    };
+    let (r, g, b) = (level(c.r), level(c.g), level(c.b));
+    let cube = (CUBE_LEVELS[r], CUBE_LEVELS[g], CUBE_LEVELS[b]);
+
+    let average = (c.r as u32 + c.g as u32 + c.b as u32) / 3;
+    let grey_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let grey_level = 8 + 10 * grey_index;
+    let grey = (grey_level, grey_level, grey_level);
+
+    if distance(rgb, grey) < distance(rgb, cube) {
+        232 + grey_index
+    } else {
+        16 + 36 * r as u8 + 6 * g as u8 + b as u8
+    }
+}
+
+/// The closest of the first `count` basic ANSI colours (8 or 16).
+pub fn to_16(c: Color, count: usize) -> u8 {
+    (0..count.min(ANSI_16.len()))
+        .min_by_key(|i| distance((c.r, c.g, c.b), ANSI_16[*i]))
+        .unwrap_or(0) as u8
+}
+
+/// The escape sequence to draw text in `c`.
+pub fn fg(c: Color, depth: ColourDepth) -> String {
+    match depth {
+        ColourDepth::TrueColour => format!("\x1b[38;2;{};{};{}m", c.r, c.g, c.b),
+        ColourDepth::Ansi256 => format!("\x1b[38;5;{}m", to_256(c)),
+        ColourDepth::Ansi16 => match to_16(c, 16) {
+            n if n < 8 => format!("\x1b[{}m", 30 + n),
+            n => format!("\x1b[{}m", 90 + n - 8),
+        },
+    }
+}
+
+/// The escape sequence to draw text on a background of `c`. With 16
+/// colours only the first eight are used, as the Linux console has no
+/// bright backgrounds.
+pub fn bg(c: Color, depth: ColourDepth) -> String {
+    match depth {
+        ColourDepth::TrueColour => format!("\x1b[48;2;{};{};{}m", c.r, c.g, c.b),
+        ColourDepth::Ansi256 => format!("\x1b[48;5;{}m", to_256(c)),
+        ColourDepth::Ansi16 => format!("\x1b[{}m", 40 + to_16(c, 8)),
+    }
+}
+
+/// Highlighted text as terminal escapes, setting each piece's foreground.
+pub fn escape_ranges(ranges: &[(Style, &str)], depth: ColourDepth) -> String {
+    let mut escaped = String::new();
+    for (style, text) in ranges {
+        escaped.push_str(&fg(style.foreground, depth));
+        escaped.push_str(text);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b, a: 0xff }
+    }
+
+    #[test]
+    fn detects_depth_from_the_environment() {
+        use ColourDepth::*;
+        assert_eq!(
+            detect_from(Some("truecolor"), Some("xterm"), Some(8)),
+            TrueColour
+        );
+        assert_eq!(
+            detect_from(None, Some("xterm-256color"), Some(256)),
+            Ansi256
+        );
+        assert_eq!(detect_from(None, Some("xterm-256color"), None), Ansi256);
+        assert_eq!(
+            detect_from(None, Some("xterm-direct"), Some(1 << 24)),
+            TrueColour
+        );
+        assert_eq!(detect_from(None, Some("linux"), Some(8)), Ansi16);
+        assert_eq!(detect_from(None, None, None), Ansi16);
+    }
+
+    #[test]
+    fn reads_max_colors_from_terminfo() {
+        // legacy header, a 3-byte name, no booleans, 14 numbers
+        let mut entry: Vec<u8> = [0o432i16, 3, 0, 14, 0, 0]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+        entry.extend(b"ab\0");
+        entry.push(0); // to an even offset
+        for n in 0..14i16 {
+            entry.extend((if n == 13 { 256i16 } else { -1 }).to_le_bytes());
+        }
+        assert_eq!(max_colours(&entry), Some(256));
+        assert_eq!(max_colours(&entry[..20]), None);
+        assert_eq!(max_colours(b"nonsense"), None);
+    }
+
+    #[test]
+    fn quantises_to_the_palettes() {
+        assert_eq!(to_256(rgb(255, 0, 0)), 196);
+        assert_eq!(to_256(rgb(0, 0, 0)), 16);
+        assert_eq!(to_256(rgb(128, 128, 128)), 244);
+        assert_eq!(to_256(rgb(0x65, 0x73, 0x7e)), 243);
+        assert_eq!(to_16(rgb(250, 10, 10), 16), 9);
+        assert_eq!(to_16(rgb(250, 10, 10), 8), 1);
+        assert_eq!(to_16(rgb(30, 30, 30), 16), 0);
+
+        let red = rgb(255, 0, 0);
+        assert_eq!(fg(red, ColourDepth::TrueColour), "\x1b[38;2;255;0;0m");
+        assert_eq!(fg(red, ColourDepth::Ansi256), "\x1b[38;5;196m");
+        assert_eq!(fg(red, ColourDepth::Ansi16), "\x1b[91m");
+        assert_eq!(bg(red, ColourDepth::Ansi16), "\x1b[41m");
+    }
+}
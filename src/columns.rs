@@ -0,0 +1,283 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// The number of cells `c` takes up when it starts at display column `col`.
+pub fn char_width(c: char, col: usize, tabstop: usize) -> usize {
+    if c == '\t' {
+        tabstop - (col % tabstop)
+    } else {
+        c.width().unwrap_or(0)
+    }
+}
+
+/// The number of cells a grapheme cluster takes up when it starts at display
+/// column `col`: two for wide East Asian characters and most emoji, none for
+/// lone combining marks.
+pub fn cluster_width(cluster: &str, col: usize, tabstop: usize) -> usize {
+    if cluster == "\t" {
+        tabstop - (col % tabstop)
+    } else {
+        cluster.width()
+    }
+}
+
+/// A grapheme cluster in a line, which the cursor moves over and the
+/// terminal draws as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cluster<'a> {
+    pub text: &'a str,
+    /// The index of its first char in the line
+    pub char_col: usize,
+    pub display_col: usize,
+    pub width: usize,
+}
+
+/// Splits `line` into grapheme clusters, working out where each is shown.
+pub fn clusters(line: &str, tabstop: usize) -> Vec<Cluster<'_>> {
+    let mut char_col = 0;
+    let mut display_col = 0;
+    line.graphemes(true)
+        .map(|text| {
+            let width = cluster_width(text, display_col, tabstop);
+            let cluster = Cluster {
+                text,
+                char_col,
+                display_col,
+                width,
+            };
+            char_col += text.chars().count();
+            display_col += width;
+            cluster
+        })
+        .collect()
+}
+
+/// The display column at which the character at `char_col` starts.
+pub fn display_col(line: &str, char_col: usize, tabstop: usize) -> usize {
+    clusters(line, tabstop)
+        .iter()
+        .take_while(|c| c.char_col < char_col)
+        .map(|c| c.width)
+        .sum()
+}
+
+enum Piece<'a> {
+    Escape(&'a str),
+    Cluster(&'a str),
+}
+
+/// Splits a (possibly ANSI-escaped) line into escape sequences and the
+/// grapheme clusters between them.
+fn pieces(escaped: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = escaped;
+    while let Some(i) = rest.find('\x1b') {
+        pieces.extend(rest[..i].graphemes(true).map(Piece::Cluster));
+        let len = escape_len(&rest[i..]);
+        pieces.push(Piece::Escape(&rest[i..i + len]));
+        rest = &rest[i + len..];
+    }
+    pieces.extend(rest.graphemes(true).map(Piece::Cluster));
+    pieces
+}
+
+/// Replaces tabs in a (possibly ANSI-escaped) line with spaces up to the next
+/// tab stop. Escape sequences are copied through and take up no columns.
+pub fn expand_tabs(escaped: &str, tabstop: usize) -> String {
+    let mut result = String::with_capacity(escaped.len());
+    let mut col = 0;
+
+    for piece in pieces(escaped) {
+        match piece {
+            Piece::Escape(e) => result.push_str(e),
+            Piece::Cluster("\t") => {
+                let w = cluster_width("\t", col, tabstop);
+                result.push_str(&" ".repeat(w));
+                col += w;
+            }
+            Piece::Cluster(c) => {
+                result.push_str(c);
+                col += c.width();
+            }
+        }
+    }
+
+    result
+}
+
+/// The columns `start..end` of a (possibly ANSI-escaped) line with no tabs.
+/// Every escape sequence is kept, so colours set before `start` still apply.
+/// Wide characters cut by either edge are replaced with spaces.
+pub fn slice_columns(escaped: &str, start: usize, end: usize) -> String {
+    let mut result = String::with_capacity(escaped.len());
+    let mut col = 0;
+
+    for piece in pieces(escaped) {
+        match piece {
+            Piece::Escape(e) => result.push_str(e),
+            Piece::Cluster(c) => {
+                let w = c.width();
+                if col >= start && col + w <= end {
+                    result.push_str(c);
+                } else {
+                    let visible = (col + w).min(end).saturating_sub(col.max(start));
+                    result.push_str(&" ".repeat(visible));
+                }
+                col += w;
+            }
+        }
+    }
+
+    result
+}
+
+/// Wraps the columns `start..end` of a (possibly ANSI-escaped) line with no
+/// tabs in the escape sequences `on` and `off`, padding the line with spaces
+/// if it ends before `end`. `on` is repeated after any escape sequence in
+/// between, in case it undid it.
+pub fn style_columns(escaped: &str, start: usize, end: usize, on: &str, off: &str) -> String {
+    let mut result = String::with_capacity(escaped.len() + 2 * on.len());
+    let mut col = 0;
+    let mut styled = false;
+
+    for piece in pieces(escaped) {
+        match piece {
+            Piece::Escape(e) => {
+                result.push_str(e);
+                if styled {
+                    result.push_str(on);
+                }
+            }
+            Piece::Cluster(c) => {
+                if !styled && col >= start && col < end {
+                    result.push_str(on);
+                    styled = true;
+                } else if styled && col >= end {
+                    result.push_str(off);
+                    styled = false;
+                }
+                result.push_str(c);
+                col += c.width();
+            }
+        }
+    }
+
+    if col < end {
+        result.push_str(&" ".repeat(start.saturating_sub(col)));
+        if !styled {
+            result.push_str(on);
+            styled = true;
+        }
+        result.push_str(&" ".repeat(end - col.max(start)));
+    }
+    if styled {
+        result.push_str(off);
+    }
+    result
+}
+
+/// Cuts a (possibly ANSI-escaped) line with no tabs down to `width`
+/// columns, padding it with spaces if it's shorter.
+pub fn fit_to_width(escaped: &str, width: usize) -> String {
+    let mut result = String::with_capacity(escaped.len() + width);
+    let mut col = 0;
+    let mut full = false;
+
+    for piece in pieces(escaped) {
+        match piece {
+            Piece::Escape(e) => result.push_str(e),
+            Piece::Cluster(c) if !full => {
+                let w = c.width();
+                if col + w <= width {
+                    result.push_str(c);
+                    col += w;
+                } else {
+                    full = true;
+                }
+            }
+            Piece::Cluster(_) => {}
+        }
+    }
+
+    result.push_str(&" ".repeat(width - col));
+    result
+}
+
+/// The length in bytes of the escape sequence `s` starts with.
+fn escape_len(s: &str) -> usize {
+    let mut chars = s.char_indices().skip(1);
+    match chars.next() {
+        Some((_, '[')) => chars
+            .find(|(_, c)| ('@'..='~').contains(c))
+            .map(|(i, c)| i + c.len_utf8())
+            .unwrap_or(s.len()),
+        Some((i, c)) => i + c.len_utf8(),
+        None => s.len(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tabs_advance_to_next_tab_stop() {
+        assert_eq!(display_col("\tx", 1, 4), 4);
+        assert_eq!(display_col("ab\tx", 3, 4), 4);
+        assert_eq!(display_col("abcd\tx", 5, 4), 8);
+        assert_eq!(display_col("abc", 2, 4), 2);
+    }
+
+    #[test]
+    fn expands_tabs_through_escape_sequences() {
+        assert_eq!(expand_tabs("a\tb", 4), "a   b");
+        assert_eq!(
+            expand_tabs("\x1b[38;2;1;2;3ma\x1b[0m\tb", 4),
+            "\x1b[38;2;1;2;3ma\x1b[0m   b"
+        );
+    }
+
+    #[test]
+    fn slices_columns_keeping_escape_sequences() {
+        assert_eq!(slice_columns("abcdef", 2, 4), "cd");
+        assert_eq!(
+            slice_columns("\x1b[1mabc\x1b[0mdef", 1, 4),
+            "\x1b[1mbc\x1b[0md"
+        );
+    }
+
+    #[test]
+    fn wide_and_combined_characters() {
+        assert_eq!(display_col("日本語x", 2, 4), 4);
+        assert_eq!(display_col("e\u{301}x", 2, 4), 1);
+        assert_eq!(display_col("👨\u{200d}👩\u{200d}👧x", 5, 4), 2);
+
+        let clusters = clusters("a日e\u{301}", 4);
+        let starts: Vec<(usize, usize)> = clusters
+            .iter()
+            .map(|c| (c.char_col, c.display_col))
+            .collect();
+        assert_eq!(starts, vec![(0, 0), (1, 1), (2, 3)]);
+
+        assert_eq!(fit_to_width("日本語", 5), "日本 ");
+        assert_eq!(slice_columns("日本語", 1, 5), " 本 ");
+    }
+
+    #[test]
+    fn styles_columns() {
+        let style = |s, start, end| style_columns(s, start, end, "<", ">");
+        assert_eq!(style("abcd", 1, 3), "a<bc>d");
+        assert_eq!(style("ab", 1, 4), "a<b  >");
+        assert_eq!(style("", 0, 1), "< >");
+        assert_eq!(style("a\x1b[0mb", 0, 2), "<a\x1b[0m<b>");
+    }
+
+    #[test]
+    fn fits_escaped_lines_to_width() {
+        assert_eq!(fit_to_width("abc", 5), "abc  ");
+        assert_eq!(
+            fit_to_width("\x1b[1mabc\x1b[0mdef", 4),
+            "\x1b[1mabc\x1b[0md"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::columns;
+use crate::language::{self, CommentMarkers};
+
+/// Comments out `lines`, or uncomments them if every non-blank line is
+/// already commented. Markers are inserted at the smallest indentation in
+/// the block so the comments line up; blank lines are left alone.
+pub fn toggle(lines: &[String], markers: &CommentMarkers, tabstop: usize) -> Vec<String> {
+    let (start, end) = match markers {
+        CommentMarkers::Line(start) => (start.as_str(), ""),
+        CommentMarkers::Block(start, end) => (start.as_str(), end.as_str()),
+    };
+    let code = lines.iter().filter(|l| !l.trim().is_empty());
+
+    if code.clone().all(|l| is_commented(l, start, end)) {
+        lines.iter().map(|l| uncomment(l, start, end)).collect()
+    } else {
+        let indent = code.map(|l| indent_width(l, tabstop)).min().unwrap_or(0);
+        lines
+            .iter()
+            .map(|l| comment(l, start, end, indent, tabstop))
+            .collect()
+    }
+}
+
+fn indent_width(line: &str, tabstop: usize) -> usize {
+    let indent = language::leading_whitespace(line);
+    columns::display_col(line, indent.chars().count(), tabstop)
+}
+
+fn is_commented(line: &str, start: &str, end: &str) -> bool {
+    let code = line.trim();
+    code.starts_with(start.trim_end()) && code.ends_with(end.trim_start())
+}
+
+fn comment(line: &str, start: &str, end: &str, indent: usize, tabstop: usize) -> String {
+    if line.trim().is_empty() {
+        return line.to_string();
+    }
+
+    let mut col = 0;
+    let split = line
+        .char_indices()
+        .find(|&(_, c)| {
+            let at_indent = col >= indent;
+            col += columns::char_width(c, col, tabstop);
+            at_indent
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(line.len());
+    let (indentation, code) = line.split_at(split);
+    if end.is_empty() {
+        format!("{}{}{}", indentation, start, code)
+    } else {
+        format!("{}{}{}{}", indentation, start, code.trim_end(), end)
+    }
+}
+
+fn uncomment(line: &str, start: &str, end: &str) -> String {
+    if line.trim().is_empty() {
+        return line.to_string();
+    }
+
+    let indentation = language::leading_whitespace(line);
+    let code = &line[indentation.len()..];
+    let code = if end.is_empty() {
+        code
+    } else {
+        code.trim_end()
+    };
+    let code = code
+        .strip_prefix(start)
+        .or_else(|| code.strip_prefix(start.trim_end()))
+        .unwrap_or(code);
+    let code = code
+        .strip_suffix(end)
+        .or_else(|| code.strip_suffix(end.trim_start()))
+        .unwrap_or(code);
+    format!("{}{}", indentation, code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn comments_at_smallest_indentation_and_back() {
+        let slashes = CommentMarkers::Line("// ".to_string());
+        let lines = strings(&["    if x {", "", "        y();", "    }"]);
+
+        let commented = toggle(&lines, &slashes, 4);
+        assert_eq!(
+            commented,
+            strings(&["    // if x {", "", "    //     y();", "    // }"])
+        );
+        assert_eq!(toggle(&commented, &slashes, 4), lines);
+    }
+
+    #[test]
+    fn mixed_lines_are_commented_rather_than_uncommented() {
+        let hash = CommentMarkers::Line("# ".to_string());
+        let lines = strings(&["# a", "b", "\t#c"]);
+        assert_eq!(
+            toggle(&lines, &hash, 4),
+            strings(&["# # a", "# b", "# \t#c"])
+        );
+        assert_eq!(
+            toggle(&strings(&["#a", "  # b"]), &hash, 4),
+            strings(&["a", "  b"])
+        );
+    }
+
+    #[test]
+    fn block_markers_wrap_each_line() {
+        let html = CommentMarkers::Block("<!-- ".to_string(), " -->".to_string());
+        let lines = strings(&["  <p>", "    x", "  </p>"]);
+
+        let commented = toggle(&lines, &html, 4);
+        assert_eq!(
+            commented,
+            strings(&["  <!-- <p> -->", "  <!--   x -->", "  <!-- </p> -->"])
+        );
+        assert_eq!(toggle(&commented, &html, 4), lines);
+    }
+}
@@ -0,0 +1,12 @@
+use std::env;
+use std::path::PathBuf;
+
+/// The directory user configuration (snippets etc.) is read from:
+/// `$XDG_CONFIG_HOME/jete`, falling back to `~/.config/jete`.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("jete"))
+}
@@ -7,6 +7,7 @@ use std::{
 use crate::pubsub::{self, Hub};
 use crate::state::{self, input_map, EditorAction};
 use crate::terminal;
+use crate::userinput;
 use crate::{
     highlight,
     pubsub::{typed_topic, TopicId},
@@ -19,38 +20,43 @@ pub fn shutdown_event_topic() -> TopicId<()> {
     typed_topic("shutdown")
 }
 
-pub fn run(fname: Option<OsString>) {
+pub fn run(fnames: Vec<OsString>) {
     let mut hub = Hub::new();
 
     highlight::spawn_highlighter(hub.clone());
+    let sizes = hub.get_receiver(terminal::size_topic());
     let terminal_thread = terminal::spawn_interface(hub.clone());
 
     let input_topic = pubsub::typed_topic::<Event>("input");
     let inputs = hub.get_receiver(input_topic.clone());
+    let pastes = hub.get_receiver(userinput::paste_topic());
+    let highlights = hub.get_receiver(highlight::HighlightState::topic());
 
     let finished = Arc::new(AtomicBool::new(false));
 
     let state_hub = hub.clone();
+    let mut core_hub = hub.clone();
 
     let other_finished = finished.clone();
 
     let result = thread::Builder::new()
         .name("core".into())
         .spawn(move || {
-            let mut state = match fname {
-                None => state::empty(state_hub),
-                Some(fname) => state::from_file(&fname, state_hub).expect("Unable to read file"),
-            };
+            let mut state = state::from_files(&fnames, state_hub).expect("Unable to read file");
+
+            let mut pending_keys = Vec::new();
 
             loop {
                 select! {
                     recv(inputs) -> input => {
                         if let Ok(e) = input {
-                            if let Some(command) = input_map(state.mode(), e) {
-                                let editor_action = state.dispatch(command);
-                                match editor_action {
+                            if let Some(command) = input_map(state.mode(), &mut pending_keys, e) {
+                                match state.dispatch(command) {
                                     EditorAction::Quit => break,
-                                    _ => {}
+                                    EditorAction::Suspend => {
+                                        let _ = core_hub.send(terminal::suspend_topic(), ());
+                                    }
+                                    EditorAction::None => {}
                                 }
                             }
                         } else {
@@ -58,6 +64,22 @@ pub fn run(fname: Option<OsString>) {
                             break;
                         }
                     }
+                    recv(pastes) -> text => {
+                        if let Ok(text) = text {
+                            pending_keys.clear();
+                            state.paste(&text);
+                        }
+                    }
+                    recv(highlights) -> hl => {
+                        if let Ok(hl) = hl {
+                            state.highlight_updated(hl);
+                        }
+                    }
+                    recv(sizes) -> size => {
+                        if let Ok(size) = size {
+                            state.resized(size);
+                        }
+                    }
                 }
             }
 
@@ -0,0 +1,237 @@
+/// A parsed command line: `[range]name[!] [args]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExCommand {
+    range: Option<LineRange>,
+    name: String,
+    bang: bool,
+    args: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineRange {
+    All,
+    Span(Address, Address),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    base: AddressBase,
+    offset: isize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressBase {
+    /// 1-based, as typed
+    Line(usize),
+    Current,
+    Last,
+}
+
+impl ExCommand {
+    pub fn parse(input: &str) -> Result<ExCommand, String> {
+        let mut rest = input.trim_start();
+
+        let range = if let Some(r) = rest.strip_prefix('%') {
+            rest = r;
+            Some(LineRange::All)
+        } else {
+            match parse_address(&mut rest)? {
+                Some(start) => {
+                    if let Some(r) = rest.strip_prefix(',') {
+                        rest = r;
+                        let end = parse_address(&mut rest)?
+                            .ok_or_else(|| format!("Missing end of range: {}", input))?;
+                        Some(LineRange::Span(start, end))
+                    } else {
+                        Some(LineRange::Span(start.clone(), start))
+                    }
+                }
+                None => None,
+            }
+        };
+
+        let rest = rest.trim_start();
+        let name_len = match rest.chars().next() {
+            Some(c) if c.is_alphabetic() => rest
+                .find(|c: char| !c.is_alphabetic())
+                .unwrap_or(rest.len()),
+            Some(c) => c.len_utf8(),
+            None => 0,
+        };
+        let (name, rest) = rest.split_at(name_len);
+        let (bang, rest) = match rest.strip_prefix('!') {
+            Some(r) if !name.is_empty() && name != "!" => (true, r),
+            _ => (false, rest),
+        };
+
+        Ok(ExCommand {
+            range,
+            name: name.to_string(),
+            bang,
+            args: rest.trim().to_string(),
+        })
+    }
+
+    pub fn range(&self) -> Option<&LineRange> {
+        self.range.as_ref()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn bang(&self) -> bool {
+        self.bang
+    }
+
+    pub fn args(&self) -> &str {
+        &self.args
+    }
+
+    /// Whether this is `name` or one of its abbreviations, at least as long
+    /// as `shortest`.
+    pub fn is(&self, shortest: &str, name: &str) -> bool {
+        self.name.len() >= shortest.len() && name.starts_with(&self.name)
+    }
+
+    /// The 0-based lines the command applies to, defaulting to the current line.
+    pub fn lines(&self, current: usize, line_count: usize) -> Result<(usize, usize), String> {
+        match &self.range {
+            None => Ok((current, current)),
+            Some(range) => range.resolve(current, line_count),
+        }
+    }
+}
+
+impl LineRange {
+    /// The first and last 0-based line numbers of the range.
+    pub fn resolve(&self, current: usize, line_count: usize) -> Result<(usize, usize), String> {
+        let last = line_count.saturating_sub(1);
+        match self {
+            LineRange::All => Ok((0, last)),
+            LineRange::Span(start, end) => {
+                let start = start.resolve(current, last)?;
+                let end = end.resolve(current, last)?;
+                if start > end {
+                    Err("Backwards range given".to_string())
+                } else {
+                    Ok((start, end))
+                }
+            }
+        }
+    }
+}
+
+impl Address {
+    fn resolve(&self, current: usize, last: usize) -> Result<usize, String> {
+        let base = match self.base {
+            AddressBase::Line(n) => n.saturating_sub(1) as isize,
+            AddressBase::Current => current as isize,
+            AddressBase::Last => last as isize,
+        };
+        let ln = base + self.offset;
+        if ln < 0 || ln as usize > last {
+            Err(format!("Line out of range: {}", ln + 1))
+        } else {
+            Ok(ln as usize)
+        }
+    }
+}
+
+fn parse_address(input: &mut &str) -> Result<Option<Address>, String> {
+    let s = *input;
+    let (base, rest) = if let Some(r) = s.strip_prefix('.') {
+        (Some(AddressBase::Current), r)
+    } else if let Some(r) = s.strip_prefix('$') {
+        (Some(AddressBase::Last), r)
+    } else {
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        if digits > 0 {
+            let n = s[..digits]
+                .parse()
+                .map_err(|_| format!("Invalid line number: {}", &s[..digits]))?;
+            (Some(AddressBase::Line(n)), &s[digits..])
+        } else {
+            (None, s)
+        }
+    };
+
+    let (offset, rest) = parse_offset(rest)?;
+    *input = rest;
+    Ok(match (base, offset) {
+        (Some(base), offset) => Some(Address {
+            base,
+            offset: offset.unwrap_or(0),
+        }),
+        (None, Some(offset)) => Some(Address {
+            base: AddressBase::Current,
+            offset,
+        }),
+        (None, None) => None,
+    })
+}
+
+fn parse_offset(s: &str) -> Result<(Option<isize>, &str), String> {
+    let sign = match s.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return Ok((None, s)),
+    };
+    let s = &s[1..];
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n = if digits == 0 {
+        1
+    } else {
+        s[..digits]
+            .parse::<isize>()
+            .map_err(|_| format!("Invalid offset: {}", &s[..digits]))?
+    };
+    Ok((Some(sign * n), &s[digits..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_names_and_arguments() {
+        let c = ExCommand::parse("set ts=4 et").unwrap();
+        assert_eq!(c.name(), "set");
+        assert_eq!(c.args(), "ts=4 et");
+        assert!(c.range().is_none());
+        assert!(c.is("se", "set"));
+        assert!(!c.is("se", "search"));
+
+        let c = ExCommand::parse("sort!").unwrap();
+        assert!(c.bang());
+        assert_eq!(c.name(), "sort");
+    }
+
+    #[test]
+    fn parses_and_resolves_ranges() {
+        let c = ExCommand::parse("%sort").unwrap();
+        assert_eq!(c.lines(3, 10), Ok((0, 9)));
+
+        let c = ExCommand::parse("2,$-1d").unwrap();
+        assert_eq!(c.name(), "d");
+        assert_eq!(c.lines(0, 10), Ok((1, 8)));
+
+        let c = ExCommand::parse(".,+2 join").unwrap();
+        assert_eq!(c.lines(4, 10), Ok((4, 6)));
+
+        let c = ExCommand::parse("12").unwrap();
+        assert_eq!(c.name(), "");
+        assert_eq!(c.lines(0, 20), Ok((11, 11)));
+
+        assert!(ExCommand::parse("5,2d").unwrap().lines(0, 10).is_err());
+        assert!(ExCommand::parse("50d").unwrap().lines(0, 10).is_err());
+    }
+
+    #[test]
+    fn non_alphabetic_command_names_are_one_character() {
+        let c = ExCommand::parse("1,3!sort -r").unwrap();
+        assert_eq!(c.name(), "!");
+        assert_eq!(c.args(), "sort -r");
+        assert!(!c.bang());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::highlight::HighlightRev;
+use crate::options::Options;
+use crate::text::Rev;
+
+/// Line numbers are padded to at least this many digits, so the text
+/// doesn't shift about as short documents grow.
+const MIN_DIGITS: usize = 3;
+
+/// Room taken by the sign column, when it's shown.
+const SIGN_WIDTH: usize = 2;
+
+/// Room taken by the `rev/hl_rev|` columns shown with `debug` set.
+const DEBUG_WIDTH: usize = 6;
+
+/// The number of columns at the left of a window showing `line_count` lines
+/// that are taken up by the gutter.
+pub fn width(line_count: usize, options: &Options) -> usize {
+    let numbers = if options.number || options.relativenumber {
+        digits(line_count).max(MIN_DIGITS) + 1
+    } else {
+        0
+    };
+    let debug = if options.debug { DEBUG_WIDTH } else { 0 };
+    sign_width(options) + numbers + debug
+}
+
+/// The number of gutter columns taken up by the sign column.
+pub fn sign_width(options: &Options) -> usize {
+    if options.signcolumn {
+        SIGN_WIDTH
+    } else {
+        0
+    }
+}
+
+/// The number shown next to (0-based) `line_number` when the cursor is on
+/// `cursor_line`: the 1-based line number, the distance from the cursor, or
+/// for hybrid numbering the line number on the cursor line and distances
+/// elsewhere.
+pub fn number(line_number: usize, cursor_line: usize, options: &Options) -> Option<usize> {
+    match (options.number, options.relativenumber) {
+        (_, true) if line_number != cursor_line => Some(line_number.abs_diff(cursor_line)),
+        (true, _) => Some(line_number + 1),
+        (false, true) => Some(0),
+        (false, false) => None,
+    }
+}
+
+/// The gutter text for the first row of a line, `width` columns wide, with
+/// the line's text and highlight revisions if they're given.
+pub fn label(width: usize, number: Option<usize>, revs: Option<(Rev, HighlightRev)>) -> String {
+    let debug = revs
+        .map(|(rev, hl_rev)| format!("{:2}/{:2}|", rev, hl_rev))
+        .unwrap_or_default();
+    let number_width = width.saturating_sub(debug.chars().count());
+    let number = match number {
+        Some(n) if number_width > 0 => format!("{:>w$} ", n, w = number_width - 1),
+        _ => " ".repeat(number_width),
+    };
+    format!("{}{}", number, debug)
+}
+
+/// The gutter text for the rows a wrapped line continues onto.
+pub fn continuation(width: usize) -> String {
+    match width {
+        0 => String::new(),
+        w => format!("{:>w$} ", "↪", w = w - 1),
+    }
+}
+
+fn digits(n: usize) -> usize {
+    n.max(1).to_string().len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        let mut options = Options::default();
+        for arg in args {
+            options.set(arg).unwrap();
+        }
+        options
+    }
+
+    #[test]
+    fn width_grows_with_document() {
+        assert_eq!(width(5, &options(&["nosigncolumn"])), 4);
+        assert_eq!(width(12345, &options(&["nosigncolumn"])), 6);
+        assert_eq!(width(12345, &options(&["nonumber"])), 2);
+        assert_eq!(width(5, &options(&["debug"])), 12);
+    }
+
+    #[test]
+    fn numbers_absolute_relative_and_hybrid() {
+        let absolute = options(&[]);
+        assert_eq!(number(4, 2, &absolute), Some(5));
+
+        let relative = options(&["nonumber", "relativenumber"]);
+        assert_eq!(number(4, 2, &relative), Some(2));
+        assert_eq!(number(0, 2, &relative), Some(2));
+        assert_eq!(number(2, 2, &relative), Some(0));
+
+        let hybrid = options(&["number", "relativenumber"]);
+        assert_eq!(number(4, 2, &hybrid), Some(2));
+        assert_eq!(number(2, 2, &hybrid), Some(3));
+
+        assert_eq!(number(2, 2, &options(&["nonumber"])), None);
+    }
+
+    #[test]
+    fn labels_fill_the_gutter() {
+        assert_eq!(label(4, Some(7), None), "  7 ");
+        assert_eq!(label(4, None, None), "    ");
+        assert_eq!(label(0, None, None), "");
+        assert_eq!(continuation(4), "  ↪ ");
+    }
+}
@@ -6,15 +6,33 @@ use std::{
     thread,
 };
 
-use syntect::{highlighting::ThemeSet, parsing::SyntaxSet};
+use syntect::{
+    highlighting::{self as syntect_highlighting, HighlightIterator, Highlighter, Theme},
+    parsing::{ParseState, Scope, ScopeStack, ScopeStackOp, SyntaxSet},
+};
+
+use crossbeam::channel::select;
+use lazy_static::lazy_static;
 
-use crate::state;
+use crate::colour;
+use crate::state::{self, TextUpdate};
 use crate::text::{LineId, Rev};
+use crate::theme;
 use crate::{
     pubsub::{self},
-    text::{LineView, TextView},
+    text::LineView,
 };
 
+lazy_static! {
+    static ref SYNTAX_SET: SyntaxSet = SyntaxSet::load_defaults_nonewlines();
+}
+
+/// The syntax definitions (and their tmPreferences metadata) shared by the
+/// highlighter and the editing rules that depend on them.
+pub fn syntax_set() -> &'static SyntaxSet {
+    &SYNTAX_SET
+}
+
 #[derive(Debug, Clone)]
 pub struct HighlightState {
     highlighted_lines: HashMap<LineId, Arc<HighlightedLine>>,
@@ -24,8 +42,7 @@ impl HighlightState {
     pub fn highlighted_line(&self, line: &LineView) -> Option<Arc<HighlightedLine>> {
         let ln = line.id();
         if let Some(hl_line) = self.highlighted_lines.get(&ln) {
-            let rev = line.rev();
-            if hl_line.highlighted_line_rev >= rev {
+            if hl_line.is_current(line.rev()) {
                 return Some(hl_line.clone());
             }
         }
@@ -33,14 +50,22 @@ impl HighlightState {
         None
     }
 
+    /// The most recent highlighting of a line, even if it has changed since.
+    pub fn line_by_id(&self, line_id: LineId) -> Option<Arc<HighlightedLine>> {
+        self.highlighted_lines.get(&line_id).cloned()
+    }
+
     pub fn topic() -> pubsub::TopicId<HighlightState> {
         pubsub::typed_topic::<HighlightState>("highlight")
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum HighlightRev {
-    Rev { id: u64 },
+    Rev {
+        id: u64,
+    },
+    #[default]
     None,
 }
 
@@ -62,10 +87,11 @@ impl Display for HighlightRev {
     }
 }
 
-impl Default for HighlightRev {
-    fn default() -> Self {
-        HighlightRev::None
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Code,
+    String,
+    Comment,
 }
 
 #[derive(Debug)]
@@ -73,6 +99,8 @@ pub struct HighlightedLine {
     highlighted_text: Arc<String>,
     highlighted_line_rev: Rev,
     highlight_rev: HighlightRev,
+    /// Character offsets at which the kind of token changes
+    token_kinds: Vec<(usize, TokenKind)>,
 }
 
 impl HighlightedLine {
@@ -83,12 +111,103 @@ impl HighlightedLine {
     pub fn rev(&self) -> HighlightRev {
         self.highlight_rev
     }
+
+    /// Whether this is the highlighting of the line as of `line_rev`, rather
+    /// than of an older version of it.
+    pub fn is_current(&self, line_rev: Rev) -> bool {
+        self.highlighted_line_rev >= line_rev
+    }
+
+    /// The kind of token the character at `col` belongs to. Columns past the
+    /// end of the line take the kind that carries on to the next line.
+    pub fn token_kind_at(&self, col: usize) -> TokenKind {
+        self.token_kinds
+            .iter()
+            .take_while(|(start, _)| *start <= col)
+            .last()
+            .map(|(_, kind)| *kind)
+            .unwrap_or(TokenKind::Code)
+    }
+
+    /// Whether text inserted at `col` would land inside a string or comment.
+    pub fn in_string_or_comment(&self, col: usize) -> bool {
+        col > 0
+            && self.token_kind_at(col - 1) != TokenKind::Code
+            && self.token_kind_at(col) != TokenKind::Code
+    }
+}
+
+struct TokenScopes {
+    string: Scope,
+    comment: Scope,
+    line_comment: Scope,
+}
+
+impl TokenScopes {
+    fn new() -> Self {
+        TokenScopes {
+            string: Scope::new("string").expect("valid scope"),
+            comment: Scope::new("comment").expect("valid scope"),
+            line_comment: Scope::new("comment.line").expect("valid scope"),
+        }
+    }
+
+    fn kind(&self, stack: &ScopeStack) -> TokenKind {
+        let scopes = stack.as_slice();
+        if scopes.iter().any(|s| self.comment.is_prefix_of(*s)) {
+            TokenKind::Comment
+        } else if scopes.iter().any(|s| self.string.is_prefix_of(*s)) {
+            TokenKind::String
+        } else {
+            TokenKind::Code
+        }
+    }
+
+    fn token_kinds(
+        &self,
+        line: &str,
+        ops: &[(usize, ScopeStackOp)],
+        stack: &mut ScopeStack,
+    ) -> Vec<(usize, TokenKind)> {
+        let mut kinds = vec![(0, self.kind(stack))];
+        for (byte_offset, op) in ops {
+            // line comments carry on up to the cursor at the end of the line
+            let ends_line_comment = *byte_offset == line.len()
+                && stack
+                    .as_slice()
+                    .iter()
+                    .any(|s| self.line_comment.is_prefix_of(*s));
+            stack.apply(op);
+            if ends_line_comment {
+                continue;
+            }
+            let kind = self.kind(stack);
+            let char_offset = line[..*byte_offset].chars().count();
+            match kinds.last_mut() {
+                Some((start, last_kind)) if *start == char_offset => *last_kind = kind,
+                Some((_, last_kind)) if *last_kind == kind => {}
+                _ => kinds.push((char_offset, kind)),
+            }
+        }
+        kinds
+    }
+}
+
+/// The latest text and colour scheme the highlighter hasn't caught up with
+#[derive(Default)]
+struct Pending {
+    update: Option<TextUpdate>,
+    theme: Option<Arc<Theme>>,
+    /// Buffers deleted since, whose lines can be forgotten
+    deleted: Vec<usize>,
 }
 
 pub fn spawn_highlighter(mut hub: pubsub::Hub) {
     let text_receiver = hub.get_receiver(state::text_update_topic());
-    let latest_state_sender: Arc<(Mutex<Option<TextView>>, Condvar)> =
-        Arc::new((Mutex::new(None), Condvar::new()));
+    let theme_receiver = hub.get_receiver(theme::topic());
+    let deleted_receiver = hub.get_receiver(state::buffer_deleted_topic());
+    let latest_state_sender: Arc<(Mutex<Pending>, Condvar)> =
+        Arc::new((Mutex::new(Pending::default()), Condvar::new()));
     let latest_state_consumer = latest_state_sender.clone();
 
     thread::Builder::new()
@@ -96,13 +215,33 @@ pub fn spawn_highlighter(mut hub: pubsub::Hub) {
         .spawn(move || {
             let (lock, cond) = &*latest_state_sender;
 
-            for state in text_receiver {
-                let mut state_holder = lock.lock().expect("publishing latest state");
-                if state_holder.is_some() {
-                    log::debug!("skipping a state update...");
+            loop {
+                select! {
+                    recv(text_receiver) -> state => {
+                        let state = match state {
+                            Ok(state) => state,
+                            Err(_) => break,
+                        };
+                        let mut pending = lock.lock().expect("publishing latest state");
+                        if pending.update.is_some() {
+                            log::debug!("skipping a state update...");
+                        }
+                        pending.update = Some(state);
+                        cond.notify_one();
+                    }
+                    recv(theme_receiver) -> theme => {
+                        if let Ok(theme) = theme {
+                            lock.lock().expect("publishing latest theme").theme = Some(theme);
+                            cond.notify_one();
+                        }
+                    }
+                    recv(deleted_receiver) -> buffer => {
+                        if let Ok(buffer) = buffer {
+                            lock.lock().expect("publishing deleted buffer").deleted.push(buffer);
+                            cond.notify_one();
+                        }
+                    }
                 }
-                *state_holder = Some(state);
-                cond.notify_one();
             }
         })
         .expect("spawning highlight thread");
@@ -110,67 +249,170 @@ pub fn spawn_highlighter(mut hub: pubsub::Hub) {
     thread::Builder::new()
         .name("highlighter".into())
         .spawn(move || {
-            let syntax_set = SyntaxSet::load_defaults_nonewlines();
-            let theme_set = ThemeSet::load_defaults();
-            let theme = &theme_set.themes["base16-ocean.dark"];
-            let syntax = syntax_set.find_syntax_by_extension("rs").unwrap();
+            let syntax_set = syntax_set();
+            let mut theme = Arc::new(theme::load(theme::DEFAULT).unwrap_or_default());
+            let token_scopes = TokenScopes::new();
+            let depth = colour::depth();
+            // the latest text of each buffer, to highlight again with a new theme
+            let mut latest: HashMap<usize, TextUpdate> = HashMap::new();
 
             log::debug!("setting up highlight thread");
 
             let mut prev_hl_state = HighlightState {
                 highlighted_lines: HashMap::new(),
             };
+            // lines of each buffer at its last highlight, so that other
+            // windows' buffers stay highlighted
+            let mut buffer_lines: HashMap<usize, HashSet<LineId>> = HashMap::new();
 
             loop {
                 let (lock, cond) = &*latest_state_consumer;
-                let text = {
-                    let mut new_state = lock.lock().expect("getting latest state");
-                    while new_state.is_none() {
-                        new_state = cond.wait(new_state).expect("getting latest state");
+                let (update, new_theme, deleted) = {
+                    let mut pending = lock.lock().expect("getting latest state");
+                    while pending.update.is_none()
+                        && pending.theme.is_none()
+                        && pending.deleted.is_empty()
+                    {
+                        pending = cond.wait(pending).expect("getting latest state");
                     }
-                    new_state.take().unwrap()
+                    (
+                        pending.update.take(),
+                        pending.theme.take(),
+                        std::mem::take(&mut pending.deleted),
+                    )
                 };
+                if !deleted.is_empty() {
+                    for buffer in &deleted {
+                        latest.remove(buffer);
+                        buffer_lines.remove(buffer);
+                    }
+                    prev_hl_state
+                        .highlighted_lines
+                        .retain(|lid, _| buffer_lines.values().any(|lines| lines.contains(lid)));
+                }
+                let theme_changed = new_theme.is_some();
+                if let Some(new_theme) = new_theme {
+                    theme = new_theme;
+                }
+                let current = update.as_ref().map(|u| u.buffer());
+                if let Some(update) = &update {
+                    latest.insert(update.buffer(), update.clone());
+                }
+                let mut updates: Vec<TextUpdate> = update.into_iter().collect();
+                if theme_changed {
+                    // every buffer's lines were coloured for the old theme
+                    updates.extend(
+                        latest
+                            .values()
+                            .filter(|u| Some(u.buffer()) != current)
+                            .cloned(),
+                    );
+                }
+                let highlighter = Highlighter::new(&theme);
 
-                log::debug!("Beginning highlight pass");
+                for update in updates {
+                    log::debug!("Beginning highlight pass");
 
-                let mut new_state = prev_hl_state.clone();
+                    let mut new_state = prev_hl_state.clone();
 
-                let mut h = syntect::easy::HighlightLines::new(syntax, theme);
+                    let language = update.language();
+                    let syntax = syntax_set
+                        .find_syntax_by_name(language.syntax_name())
+                        .or_else(|| {
+                            language
+                                .extensions()
+                                .iter()
+                                .find_map(|ext| syntax_set.find_syntax_by_extension(ext))
+                        })
+                        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+                    let text = update.text();
 
-                let mut seen_lines = HashSet::with_capacity(prev_hl_state.highlighted_lines.len());
+                    let mut parse_state = ParseState::new(syntax);
+                    let mut style_state =
+                        syntect_highlighting::HighlightState::new(&highlighter, ScopeStack::new());
+                    let mut scope_stack = ScopeStack::new();
 
-                for line in text.iter_lines() {
-                    let line_text = line.content_str();
-                    seen_lines.insert(line.id());
-                    let ranges = h.highlight(&line_text, &syntax_set);
-                    let escaped = syntect::util::as_24_bit_terminal_escaped(&ranges[..], false);
-                    let highlight_rev = HighlightRev::from(&escaped, line.id());
+                    let mut seen_lines =
+                        HashSet::with_capacity(prev_hl_state.highlighted_lines.len());
 
-                    new_state.highlighted_lines.insert(
-                        line.id(),
-                        Arc::new(HighlightedLine {
-                            highlighted_text: Arc::new(escaped),
-                            highlighted_line_rev: line.max_rev_before(),
-                            highlight_rev,
-                        }),
-                    );
+                    for line in text.iter_lines() {
+                        let line_text = line.content_str();
+                        seen_lines.insert(line.id());
+                        let ops = parse_state.parse_line(&line_text, syntax_set);
+                        let ranges: Vec<_> = HighlightIterator::new(
+                            &mut style_state,
+                            &ops[..],
+                            &line_text,
+                            &highlighter,
+                        )
+                        .collect();
+                        let token_kinds =
+                            token_scopes.token_kinds(&line_text, &ops, &mut scope_stack);
+                        let escaped = colour::escape_ranges(&ranges, depth);
+                        let highlight_rev = HighlightRev::from(&escaped, line.id());
 
-                    if line.line_number() > 0 && line.line_number() % 20 == 0 {
-                        let _ = hub.send(HighlightState::topic(), new_state.clone());
+                        new_state.highlighted_lines.insert(
+                            line.id(),
+                            Arc::new(HighlightedLine {
+                                highlighted_text: Arc::new(escaped),
+                                highlighted_line_rev: line.max_rev_before(),
+                                highlight_rev,
+                                token_kinds,
+                            }),
+                        );
+
+                        if line.line_number() > 0 && line.line_number() % 20 == 0 {
+                            let _ = hub.send(HighlightState::topic(), new_state.clone());
+                        }
                     }
-                }
 
-                if let Err(_) = hub.send(HighlightState::topic(), new_state.clone()) {
-                    log::debug!("Nobody is listening for highlight updates");
-                }
+                    if hub
+                        .send(HighlightState::topic(), new_state.clone())
+                        .is_err()
+                    {
+                        log::debug!("Nobody is listening for highlight updates");
+                    }
 
-                log::debug!("Highlight pass finished");
+                    log::debug!("Highlight pass finished");
 
-                prev_hl_state = new_state;
-                prev_hl_state
-                    .highlighted_lines
-                    .retain(|lid, _| seen_lines.contains(lid));
+                    prev_hl_state = new_state;
+                    buffer_lines.insert(update.buffer(), seen_lines);
+                    prev_hl_state
+                        .highlighted_lines
+                        .retain(|lid, _| buffer_lines.values().any(|lines| lines.contains(lid)));
+                }
             }
         })
         .expect("Initializing highlighter");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_kinds_follow_strings_and_comments() {
+        let syntax_set = syntax_set();
+        let syntax = syntax_set.find_syntax_by_name("Rust").unwrap();
+        let mut parse_state = ParseState::new(syntax);
+        let mut stack = ScopeStack::new();
+        let scopes = TokenScopes::new();
+
+        let line = r#"f("a(b", c); // x"#;
+        let ops = parse_state.parse_line(line, syntax_set);
+        let hl = HighlightedLine {
+            highlighted_text: Arc::new(String::new()),
+            highlighted_line_rev: Rev::default(),
+            highlight_rev: HighlightRev::None,
+            token_kinds: scopes.token_kinds(line, &ops, &mut stack),
+        };
+
+        assert_eq!(hl.token_kind_at(0), TokenKind::Code);
+        assert_eq!(hl.token_kind_at(4), TokenKind::String);
+        assert!(hl.in_string_or_comment(4));
+        assert!(!hl.in_string_or_comment(2));
+        assert!(!hl.in_string_or_comment(8));
+        assert_eq!(hl.token_kind_at(14), TokenKind::Comment);
+        assert!(hl.in_string_or_comment(line.len()));
+    }
+}
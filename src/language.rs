@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::config;
+use crate::highlight;
+
+/// Editing behaviour that depends on the kind of file being edited. Each
+/// language names the syntect syntax used to highlight it, so that the
+/// editing rules and highlighting are always chosen together.
+#[derive(Debug)]
+pub struct Language {
+    name: &'static str,
+    syntax_name: &'static str,
+    extensions: &'static [&'static str],
+    indent: IndentRules,
+    /// Opening and closing characters typed in pairs
+    pairs: &'static [(char, char)],
+    /// Shell command that formats the buffer from stdin to stdout
+    formatter: Option<&'static str>,
+}
+
+#[derive(Debug)]
+struct IndentRules {
+    /// A line ending (ignoring trailing whitespace) in one of these opens a block
+    open_block: &'static [char],
+    /// Typing one of these as the first character on a line dedents it
+    electric: &'static [char],
+    /// A line starting with one of these keywords closes the current block
+    dedent_after: &'static [&'static str],
+}
+
+const BRACES: IndentRules = IndentRules {
+    open_block: &['{', '[', '('],
+    electric: &['}', ']', ')'],
+    dedent_after: &[],
+};
+
+const BRACKET_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}')];
+const CODE_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')];
+// single quotes are also used for lifetimes
+const RUST_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')];
+
+const NO_INDENT_RULES: IndentRules = IndentRules {
+    open_block: &[],
+    electric: &[],
+    dedent_after: &[],
+};
+
+static LANGUAGES: &[Language] = &[
+    Language {
+        name: "rust",
+        syntax_name: "Rust",
+        extensions: &["rs"],
+        indent: BRACES,
+        pairs: RUST_PAIRS,
+        formatter: Some("rustfmt --edition 2021"),
+    },
+    Language {
+        name: "c",
+        syntax_name: "C",
+        extensions: &["c", "h"],
+        indent: BRACES,
+        pairs: CODE_PAIRS,
+        formatter: Some("clang-format"),
+    },
+    Language {
+        name: "cpp",
+        syntax_name: "C++",
+        extensions: &["cpp", "cc", "cxx", "hpp", "hh"],
+        indent: BRACES,
+        pairs: CODE_PAIRS,
+        formatter: Some("clang-format"),
+    },
+    Language {
+        name: "go",
+        syntax_name: "Go",
+        extensions: &["go"],
+        indent: BRACES,
+        pairs: CODE_PAIRS,
+        formatter: Some("gofmt"),
+    },
+    Language {
+        name: "java",
+        syntax_name: "Java",
+        extensions: &["java"],
+        indent: BRACES,
+        pairs: CODE_PAIRS,
+        formatter: None,
+    },
+    Language {
+        name: "javascript",
+        syntax_name: "JavaScript",
+        extensions: &["js", "mjs", "cjs", "ts", "jsx", "tsx"],
+        indent: BRACES,
+        pairs: CODE_PAIRS,
+        formatter: Some("prettier --parser babel"),
+    },
+    Language {
+        name: "json",
+        syntax_name: "JSON",
+        extensions: &["json"],
+        indent: BRACES,
+        pairs: CODE_PAIRS,
+        formatter: Some("prettier --parser json"),
+    },
+    Language {
+        name: "css",
+        syntax_name: "CSS",
+        extensions: &["css"],
+        indent: BRACES,
+        pairs: CODE_PAIRS,
+        formatter: Some("prettier --parser css"),
+    },
+    Language {
+        name: "python",
+        syntax_name: "Python",
+        extensions: &["py", "pyi"],
+        indent: IndentRules {
+            open_block: &[':', '{', '[', '('],
+            electric: &['}', ']', ')'],
+            dedent_after: &["return", "pass", "break", "continue", "raise"],
+        },
+        pairs: CODE_PAIRS,
+        formatter: Some("black --quiet -"),
+    },
+    Language {
+        name: "shell",
+        syntax_name: "Bourne Again Shell (bash)",
+        extensions: &["sh", "bash"],
+        indent: BRACES,
+        pairs: CODE_PAIRS,
+        formatter: Some("shfmt"),
+    },
+    Language {
+        name: "ruby",
+        syntax_name: "Ruby",
+        extensions: &["rb"],
+        indent: NO_INDENT_RULES,
+        pairs: CODE_PAIRS,
+        formatter: None,
+    },
+    Language {
+        name: "yaml",
+        syntax_name: "YAML",
+        extensions: &["yaml", "yml"],
+        indent: IndentRules {
+            open_block: &[':'],
+            electric: &[],
+            dedent_after: &[],
+        },
+        pairs: CODE_PAIRS,
+        formatter: Some("prettier --parser yaml"),
+    },
+    Language {
+        name: "html",
+        syntax_name: "HTML",
+        extensions: &["html", "htm"],
+        indent: NO_INDENT_RULES,
+        pairs: &[('"', '"'), ('\'', '\'')],
+        formatter: Some("prettier --parser html"),
+    },
+    Language {
+        name: "markdown",
+        syntax_name: "Markdown",
+        extensions: &["md", "markdown"],
+        indent: NO_INDENT_RULES,
+        pairs: BRACKET_PAIRS,
+        formatter: Some("prettier --parser markdown"),
+    },
+];
+
+static PLAIN_TEXT: Language = Language {
+    name: "text",
+    syntax_name: "Plain Text",
+    extensions: &["txt"],
+    indent: NO_INDENT_RULES,
+    pairs: BRACKET_PAIRS,
+    formatter: None,
+};
+
+pub fn plain_text() -> &'static Language {
+    &PLAIN_TEXT
+}
+
+/// Plain text formatted by `formatter`, so tests don't depend on which
+/// formatters are installed.
+#[cfg(test)]
+pub fn formatted_by(formatter: &'static str) -> &'static Language {
+    Box::leak(Box::new(Language {
+        formatter: Some(formatter),
+        indent: NO_INDENT_RULES,
+        ..PLAIN_TEXT
+    }))
+}
+
+pub fn for_path(path: &Path) -> &'static Language {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| {
+            LANGUAGES
+                .iter()
+                .find(|l| l.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+        })
+        .unwrap_or(&PLAIN_TEXT)
+}
+
+/// Reads formatter commands from lines of `<language> = <command>`, skipping
+/// blank lines and `#` comments.
+fn parse_formatters(contents: &str) -> Result<HashMap<String, String>, String> {
+    let mut formatters = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, command) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected <language> = <command>", i + 1))?;
+        formatters.insert(name.trim().to_string(), command.trim().to_string());
+    }
+    Ok(formatters)
+}
+
+/// How lines are commented out, taken from the syntax's tmPreferences.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommentMarkers {
+    Line(String),
+    Block(String, String),
+}
+
+impl Language {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn syntax_name(&self) -> &'static str {
+        self.syntax_name
+    }
+
+    pub fn extensions(&self) -> &'static [&'static str] {
+        self.extensions
+    }
+
+    pub fn formatter(&self) -> Option<&'static str> {
+        self.formatter
+    }
+
+    /// The command that formats this language: the one given for it in
+    /// `<config dir>/formatters` if there is one, or else the built-in one.
+    pub fn configured_formatter(&self) -> Result<Option<String>, String> {
+        self.formatter_from(
+            config::config_dir()
+                .map(|d| d.join("formatters"))
+                .as_deref(),
+        )
+    }
+
+    fn formatter_from(&self, formatters: Option<&Path>) -> Result<Option<String>, String> {
+        let default = self.formatter.map(|f| f.to_string());
+        let path = match formatters {
+            Some(path) => path,
+            None => return Ok(default),
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(default),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let mut formatters =
+            parse_formatters(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        match formatters.remove(self.name) {
+            // an empty command turns the formatter off
+            Some(command) => Ok(Some(command).filter(|c| !c.is_empty())),
+            None => Ok(default),
+        }
+    }
+
+    /// The markers used to toggle comments, preferring line comments.
+    pub fn comment_markers(&self) -> Option<CommentMarkers> {
+        let syntax_set = highlight::syntax_set();
+        let syntax = syntax_set.find_syntax_by_name(self.syntax_name)?;
+        let metadata = syntax_set.metadata().metadata_for_scope(&[syntax.scope]);
+        metadata
+            .line_comment()
+            .map(|start| CommentMarkers::Line(start.to_string()))
+            .or_else(|| {
+                metadata
+                    .block_comment()
+                    .map(|(start, end)| CommentMarkers::Block(start.to_string(), end.to_string()))
+            })
+    }
+
+    /// The closing character to insert when `open` is typed.
+    pub fn closing_pair(&self, open: char) -> Option<char> {
+        self.pairs
+            .iter()
+            .find(|(o, _)| *o == open)
+            .map(|(_, close)| *close)
+    }
+
+    pub fn is_closing_pair(&self, c: char) -> bool {
+        self.pairs.iter().any(|(_, close)| *close == c)
+    }
+
+    /// The indentation for a line opened by breaking a line at the cursor,
+    /// where `before_cursor` is the content of the line up to the cursor.
+    pub fn newline_indent(&self, before_cursor: &str, indent_unit: &str) -> String {
+        let base = leading_whitespace(before_cursor);
+        let trimmed = before_cursor.trim();
+
+        if trimmed
+            .chars()
+            .last()
+            .map(|c| self.indent.open_block.contains(&c))
+            .unwrap_or(false)
+        {
+            format!("{}{}", base, indent_unit)
+        } else if self.closes_block(trimmed) {
+            dedent(base, indent_unit).to_string()
+        } else {
+            base.to_string()
+        }
+    }
+
+    /// Whether typing `c` at the start of a line should move it back to the
+    /// indentation of the block it closes.
+    /// A guess from the line's text alone at whether `col` is inside a string
+    /// or line comment, for when the line hasn't been highlighted as it is.
+    pub fn in_string_or_comment(&self, line: &str, col: usize) -> bool {
+        let comment: Option<Vec<char>> = match self.comment_markers() {
+            Some(CommentMarkers::Line(start)) => Some(start.chars().collect()),
+            _ => None,
+        };
+        let before: Vec<char> = line.chars().take(col).collect();
+        let mut quote = None;
+        let mut i = 0;
+        while i < before.len() {
+            let c = before[i];
+            match quote {
+                Some(_) if c == '\\' => i += 1,
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if self.pairs.iter().any(|(o, close)| *o == c && *close == c) => {
+                    quote = Some(c)
+                }
+                None if comment
+                    .as_ref()
+                    .is_some_and(|start| before[i..].starts_with(start)) =>
+                {
+                    return true
+                }
+                None => {}
+            }
+            i += 1;
+        }
+        quote.is_some()
+    }
+
+    pub fn is_electric(&self, c: char) -> bool {
+        self.indent.electric.contains(&c)
+    }
+
+    fn closes_block(&self, trimmed_line: &str) -> bool {
+        self.indent.dedent_after.iter().any(|kw| {
+            trimmed_line
+                .strip_prefix(kw)
+                .map(|rest| rest.is_empty() || rest.starts_with(|c: char| !c.is_alphanumeric()))
+                .unwrap_or(false)
+        })
+    }
+}
+
+pub fn matching_open(close: char) -> Option<char> {
+    match close {
+        ')' => Some('('),
+        ']' => Some('['),
+        '}' => Some('{'),
+        _ => None,
+    }
+}
+
+pub fn leading_whitespace(line: &str) -> &str {
+    let end = line
+        .find(|c: char| !c.is_whitespace())
+        .unwrap_or(line.len());
+    &line[..end]
+}
+
+/// Removes one level of indentation from the end of `indent`.
+pub fn dedent<'a>(indent: &'a str, indent_unit: &str) -> &'a str {
+    if let Some(shorter) = indent.strip_suffix(indent_unit) {
+        shorter
+    } else if let Some(shorter) = indent.strip_suffix('\t') {
+        shorter
+    } else {
+        let trailing_spaces = indent.len() - indent.trim_end_matches(' ').len();
+        &indent[..indent.len() - trailing_spaces.min(indent_unit.len().max(1))]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn guesses_strings_and_comments_from_text() {
+        let rust = for_path(Path::new("a.rs"));
+        assert!(rust.in_string_or_comment(r#"f("a(b"#, 5));
+        assert!(!rust.in_string_or_comment(r#"f("a", b"#, 7));
+        assert!(rust.in_string_or_comment(r#"f("a\"b"#, 7));
+        assert!(rust.in_string_or_comment("x; // y", 7));
+        assert!(!rust.in_string_or_comment(r#""//" x"#, 5));
+        assert!(!plain_text().in_string_or_comment("\"a", 2));
+    }
+
+    #[test]
+    fn formatters_can_be_configured_per_language() {
+        let path = std::env::temp_dir().join(format!("jete-formatters-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# comment\n\nrust = rustfmt --edition 2018\npython =\n",
+        )
+        .unwrap();
+        let rust = for_path(Path::new("a.rs"));
+        let python = for_path(Path::new("a.py"));
+        let go = for_path(Path::new("a.go"));
+
+        assert_eq!(
+            rust.formatter_from(Some(&path)),
+            Ok(Some("rustfmt --edition 2018".to_string()))
+        );
+        assert_eq!(python.formatter_from(Some(&path)), Ok(None));
+        assert_eq!(
+            go.formatter_from(Some(&path)),
+            Ok(Some("gofmt".to_string()))
+        );
+
+        fs::write(&path, "rust rustfmt\n").unwrap();
+        assert!(rust
+            .formatter_from(Some(&path))
+            .unwrap_err()
+            .contains("line 1"));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            rust.formatter_from(Some(&path)),
+            Ok(Some("rustfmt --edition 2021".to_string()))
+        );
+    }
+
+    #[test]
+    fn selects_language_by_extension() {
+        assert_eq!(for_path(Path::new("src/main.rs")).name(), "rust");
+        assert_eq!(for_path(Path::new("setup.PY")).name(), "python");
+        assert_eq!(for_path(Path::new("README")).name(), "text");
+    }
+
+    #[test]
+    fn new_line_keeps_indentation() {
+        let rust = for_path(Path::new("a.rs"));
+        assert_eq!(rust.newline_indent("    let x = 5;", "    "), "    ");
+        assert_eq!(rust.newline_indent("\tfoo();", "    "), "\t");
+    }
+
+    #[test]
+    fn new_line_indents_after_block_opener() {
+        let rust = for_path(Path::new("a.rs"));
+        assert_eq!(rust.newline_indent("    fn main() {  ", "    "), "        ");
+
+        let python = for_path(Path::new("a.py"));
+        assert_eq!(python.newline_indent("def f(x):", "    "), "    ");
+        assert_eq!(plain_text().newline_indent("def f(x):", "    "), "");
+    }
+
+    #[test]
+    fn new_line_dedents_after_block_closing_keyword() {
+        let python = for_path(Path::new("a.py"));
+        assert_eq!(python.newline_indent("        return x", "    "), "    ");
+        assert_eq!(
+            python.newline_indent("        returned = 1", "    "),
+            "        "
+        );
+    }
+
+    #[test]
+    fn dedent_removes_one_level() {
+        assert_eq!(dedent("        ", "    "), "    ");
+        assert_eq!(dedent("\t\t", "    "), "\t");
+        assert_eq!(dedent("  ", "    "), "");
+        assert_eq!(dedent("", "    "), "");
+    }
+}
@@ -0,0 +1,412 @@
+/// How the text area is divided up between windows: a binary tree of splits
+/// with windows at the leaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layout {
+    Window(WindowId),
+    Split {
+        orientation: Orientation,
+        /// Rows or columns given to `first`; `None` shares the space evenly
+        first_size: Option<u16>,
+        first: Box<Layout>,
+        second: Box<Layout>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WindowId(usize);
+
+impl WindowId {
+    pub fn next(self) -> WindowId {
+        WindowId(self.0 + 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    /// One window above the other
+    Stacked,
+    /// Windows next to each other, with a separator column between them
+    SideBySide,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// An area of the screen, in 1-based terminal coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// Where each window goes on screen.
+#[derive(Debug, Default)]
+pub struct Arrangement {
+    pub windows: Vec<(WindowId, Rect)>,
+    /// Columns drawn between side-by-side windows
+    pub separators: Vec<Rect>,
+}
+
+impl Arrangement {
+    pub fn rect_of(&self, id: WindowId) -> Option<Rect> {
+        self.windows
+            .iter()
+            .find(|(w, _)| *w == id)
+            .map(|(_, rect)| *rect)
+    }
+}
+
+/// The part of a `(width, height)` screen given to windows, leaving the
+/// bottom two rows for messages and the command line, and the top row for
+/// the tab bar if there is one.
+pub fn text_area((width, height): (u16, u16), tab_bar: bool) -> Rect {
+    let top = tab_bar as u16;
+    Rect {
+        x: 1,
+        y: 1 + top,
+        width,
+        height: height.saturating_sub(2 + top),
+    }
+}
+
+/// Where a view of `extent` cells should start, moving as little as possible
+/// from `first` so that `target` is shown with `margin` cells around it.
+pub fn scroll_to_show(first: usize, target: usize, extent: usize, margin: usize) -> usize {
+    let margin = margin.min(extent.saturating_sub(1) / 2);
+    if target < first + margin {
+        target.saturating_sub(margin)
+    } else if target + margin >= first + extent {
+        target + margin + 1 - extent
+    } else {
+        first
+    }
+}
+
+impl Rect {
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        (self.x..self.right()).contains(&x) && (self.y..self.bottom()).contains(&y)
+    }
+
+    fn right(&self) -> u16 {
+        self.x + self.width
+    }
+
+    fn bottom(&self) -> u16 {
+        self.y + self.height
+    }
+}
+
+impl Layout {
+    pub fn windows(&self) -> Vec<WindowId> {
+        match self {
+            Layout::Window(id) => vec![*id],
+            Layout::Split { first, second, .. } => {
+                let mut windows = first.windows();
+                windows.extend(second.windows());
+                windows
+            }
+        }
+    }
+
+    pub fn contains(&self, id: WindowId) -> bool {
+        match self {
+            Layout::Window(w) => *w == id,
+            Layout::Split { first, second, .. } => first.contains(id) || second.contains(id),
+        }
+    }
+
+    /// Splits `target`'s space with `new`, which goes above or to the left.
+    pub fn split(&mut self, target: WindowId, new: WindowId, orientation: Orientation) -> bool {
+        match self {
+            Layout::Window(id) if *id == target => {
+                *self = Layout::Split {
+                    orientation,
+                    first_size: None,
+                    first: Box::new(Layout::Window(new)),
+                    second: Box::new(Layout::Window(target)),
+                };
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split { first, second, .. } => {
+                first.split(target, new, orientation) || second.split(target, new, orientation)
+            }
+        }
+    }
+
+    /// Removes `target`, giving its space to its sibling. The last window
+    /// can't be closed.
+    pub fn close(&mut self, target: WindowId) -> bool {
+        match self {
+            Layout::Window(_) => false,
+            Layout::Split { first, second, .. } => {
+                if **first == Layout::Window(target) {
+                    *self = (**second).clone();
+                    true
+                } else if **second == Layout::Window(target) {
+                    *self = (**first).clone();
+                    true
+                } else {
+                    first.close(target) || second.close(target)
+                }
+            }
+        }
+    }
+
+    pub fn arrange(&self, area: Rect) -> Arrangement {
+        let mut arrangement = Arrangement::default();
+        self.arrange_into(area, &mut arrangement);
+        arrangement
+    }
+
+    fn arrange_into(&self, area: Rect, arrangement: &mut Arrangement) {
+        match self {
+            Layout::Window(id) => arrangement.windows.push((*id, area)),
+            Layout::Split {
+                orientation,
+                first,
+                second,
+                ..
+            } => {
+                let (a, b) = self.split_areas(area);
+                if *orientation == Orientation::SideBySide {
+                    arrangement.separators.push(Rect {
+                        x: a.right(),
+                        width: 1,
+                        ..a
+                    });
+                }
+                first.arrange_into(a, arrangement);
+                second.arrange_into(b, arrangement);
+            }
+        }
+    }
+
+    /// The areas of a split's two children.
+    fn split_areas(&self, area: Rect) -> (Rect, Rect) {
+        let (orientation, first_size) = match self {
+            Layout::Window(_) => return (area, area),
+            Layout::Split {
+                orientation,
+                first_size,
+                ..
+            } => (*orientation, *first_size),
+        };
+
+        match orientation {
+            Orientation::Stacked => {
+                let size = Self::clamp_size(first_size, area.height, 0);
+                (
+                    Rect {
+                        height: size,
+                        ..area
+                    },
+                    Rect {
+                        y: area.y + size,
+                        height: area.height - size,
+                        ..area
+                    },
+                )
+            }
+            Orientation::SideBySide => {
+                let size = Self::clamp_size(first_size, area.width, 1);
+                (
+                    Rect {
+                        width: size,
+                        ..area
+                    },
+                    Rect {
+                        x: area.x + size + 1,
+                        width: area.width.saturating_sub(size + 1),
+                        ..area
+                    },
+                )
+            }
+        }
+    }
+
+    fn clamp_size(size: Option<u16>, extent: u16, separator: u16) -> u16 {
+        let available = extent.saturating_sub(separator);
+        let size = size.unwrap_or(available / 2);
+        size.clamp(1, available.saturating_sub(1).max(1))
+    }
+
+    /// Grows (or shrinks, for negative `delta`) `target` along `orientation`
+    /// by moving the nearest enclosing split of that orientation.
+    pub fn resize(
+        &mut self,
+        target: WindowId,
+        orientation: Orientation,
+        delta: i32,
+        area: Rect,
+    ) -> bool {
+        let (a, b) = self.split_areas(area);
+        match self {
+            Layout::Window(_) => false,
+            Layout::Split {
+                orientation: split_orientation,
+                first_size,
+                first,
+                second,
+            } => {
+                let in_first = first.contains(target);
+                if !in_first && !second.contains(target) {
+                    return false;
+                }
+                let resized_deeper = if in_first {
+                    first.resize(target, orientation, delta, a)
+                } else {
+                    second.resize(target, orientation, delta, b)
+                };
+                if resized_deeper {
+                    return true;
+                }
+                if *split_orientation != orientation {
+                    return false;
+                }
+
+                let current = match orientation {
+                    Orientation::Stacked => a.height,
+                    Orientation::SideBySide => a.width,
+                } as i32;
+                let size = if in_first {
+                    current + delta
+                } else {
+                    current - delta
+                };
+                *first_size = Some(size.max(1) as u16);
+                true
+            }
+        }
+    }
+
+    /// The window next to `from` on the given side, preferring the one
+    /// closest to `from`'s top-left corner.
+    pub fn neighbour(&self, from: WindowId, side: Side, area: Rect) -> Option<WindowId> {
+        let arrangement = self.arrange(area);
+        let r = arrangement.rect_of(from)?;
+
+        arrangement
+            .windows
+            .iter()
+            .filter(|(id, _)| *id != from)
+            .filter(|(_, o)| match side {
+                Side::Left => o.right() + 1 == r.x && overlaps(o.y, o.bottom(), r.y, r.bottom()),
+                Side::Right => r.right() + 1 == o.x && overlaps(o.y, o.bottom(), r.y, r.bottom()),
+                Side::Up => o.bottom() == r.y && overlaps(o.x, o.right(), r.x, r.right()),
+                Side::Down => r.bottom() == o.y && overlaps(o.x, o.right(), r.x, r.right()),
+            })
+            .min_by_key(|(_, o)| match side {
+                Side::Left | Side::Right => (o.y as i32 - r.y as i32).abs(),
+                Side::Up | Side::Down => (o.x as i32 - r.x as i32).abs(),
+            })
+            .map(|(id, _)| *id)
+    }
+}
+
+fn overlaps(a_start: u16, a_end: u16, b_start: u16, b_end: u16) -> bool {
+    a_start < b_end && b_start < a_end
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SCREEN: Rect = Rect {
+        x: 1,
+        y: 1,
+        width: 81,
+        height: 20,
+    };
+
+    fn ids() -> (WindowId, WindowId, WindowId) {
+        let a = WindowId::default();
+        (a, a.next(), a.next().next())
+    }
+
+    #[test]
+    fn splits_share_space_and_close_back() {
+        let (a, b, c) = ids();
+        let mut layout = Layout::Window(a);
+        assert!(layout.split(a, b, Orientation::SideBySide));
+        assert!(layout.split(a, c, Orientation::Stacked));
+        assert_eq!(layout.windows(), vec![b, c, a]);
+
+        let arrangement = layout.arrange(SCREEN);
+        assert_eq!(
+            arrangement.rect_of(b),
+            Some(Rect {
+                x: 1,
+                y: 1,
+                width: 40,
+                height: 20
+            })
+        );
+        assert_eq!(
+            arrangement.rect_of(c),
+            Some(Rect {
+                x: 42,
+                y: 1,
+                width: 40,
+                height: 10
+            })
+        );
+        assert_eq!(arrangement.rect_of(a).map(|r| r.y), Some(11));
+        assert_eq!(arrangement.separators.len(), 1);
+
+        assert!(layout.close(b));
+        assert!(layout.close(c));
+        assert!(!layout.close(a));
+        assert_eq!(layout, Layout::Window(a));
+    }
+
+    #[test]
+    fn scrolls_just_enough_to_keep_margin() {
+        assert_eq!(scroll_to_show(0, 5, 10, 2), 0);
+        assert_eq!(scroll_to_show(0, 9, 10, 2), 2);
+        assert_eq!(scroll_to_show(10, 11, 10, 2), 9);
+        assert_eq!(scroll_to_show(10, 1, 10, 2), 0);
+        assert_eq!(scroll_to_show(0, 50, 4, 10), 48);
+    }
+
+    #[test]
+    fn finds_neighbours() {
+        let (a, b, c) = ids();
+        let mut layout = Layout::Window(a);
+        layout.split(a, b, Orientation::SideBySide);
+        layout.split(a, c, Orientation::Stacked);
+
+        assert_eq!(layout.neighbour(b, Side::Right, SCREEN), Some(c));
+        assert_eq!(layout.neighbour(a, Side::Left, SCREEN), Some(b));
+        assert_eq!(layout.neighbour(c, Side::Down, SCREEN), Some(a));
+        assert_eq!(layout.neighbour(a, Side::Up, SCREEN), Some(c));
+        assert_eq!(layout.neighbour(b, Side::Left, SCREEN), None);
+    }
+
+    #[test]
+    fn resizes_nearest_matching_split() {
+        let (a, b, c) = ids();
+        let mut layout = Layout::Window(a);
+        layout.split(a, b, Orientation::SideBySide);
+        layout.split(a, c, Orientation::Stacked);
+
+        assert!(layout.resize(a, Orientation::Stacked, 3, SCREEN));
+        assert_eq!(
+            layout.arrange(SCREEN).rect_of(a).map(|r| r.height),
+            Some(13)
+        );
+
+        assert!(layout.resize(a, Orientation::SideBySide, 5, SCREEN));
+        assert_eq!(layout.arrange(SCREEN).rect_of(b).map(|r| r.width), Some(35));
+
+        let mut single = Layout::Window(a);
+        assert!(!single.resize(a, Orientation::Stacked, 1, SCREEN));
+    }
+}
@@ -1,8 +1,22 @@
+pub mod colour;
+pub mod columns;
+pub mod comment;
+pub mod config;
 pub mod display;
 pub mod editor;
+pub mod excommand;
+pub mod gutter;
 pub mod highlight;
+pub mod language;
+pub mod layout;
+pub mod options;
 pub mod pubsub;
+pub mod shell;
+pub mod signs;
+pub mod snippet;
 pub mod state;
 pub mod terminal;
 pub mod text;
+pub mod theme;
 pub mod userinput;
+pub mod wrap;
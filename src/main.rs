@@ -1,4 +1,4 @@
-use jete::editor;
+use jete::{editor, terminal};
 use log::LevelFilter;
 use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
 use log4rs::append::rolling_file::{
@@ -15,9 +15,9 @@ fn main() {
     let mut args = env::args_os();
     args.next().unwrap(); // safe: just the process name
 
-    let file = args.next();
+    let files = args.collect();
 
-    editor::run(file);
+    editor::run(files);
 }
 
 fn configure_logging() {
@@ -42,6 +42,9 @@ fn configure_logging() {
 
     let existing_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |pi| {
+        // so the message is printed where the user can see it
+        terminal::restore();
+
         let (file, line) = pi
             .location()
             .map(|l| (l.file(), l.line()))
@@ -0,0 +1,177 @@
+/// Editor settings changed with `:set`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub tabstop: usize,
+    pub shiftwidth: usize,
+    pub expandtab: bool,
+    pub autopairs: bool,
+    /// Run the language's formatter over the buffer before writing it
+    pub formatonsave: bool,
+    /// Strip trailing whitespace from lines changed since the last save
+    pub trimtrailing: bool,
+    /// Remove blank lines at the end of the file, leaving one final newline
+    pub fixeol: bool,
+    /// Rewrite indentation to match `expandtab` on save
+    pub retab: bool,
+    /// Soft-wrap lines wider than the window onto extra display rows
+    pub wrap: bool,
+    /// Rows kept visible above and below the cursor when scrolling
+    pub scrolloff: usize,
+    /// Columns kept visible either side of the cursor when scrolling
+    /// sideways
+    pub sidescrolloff: usize,
+    /// Show line numbers in the gutter
+    pub number: bool,
+    /// Show line numbers relative to the cursor line; with `number` too, the
+    /// cursor line shows its own number
+    pub relativenumber: bool,
+    /// Leave room in the gutter for signs such as diagnostics and bookmarks
+    pub signcolumn: bool,
+    /// Highlight the line the cursor is on
+    pub cursorline: bool,
+    /// Show each line's text and highlight revisions in the gutter
+    pub debug: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            tabstop: 8,
+            shiftwidth: 4,
+            expandtab: false,
+            autopairs: true,
+            formatonsave: false,
+            trimtrailing: false,
+            fixeol: false,
+            retab: false,
+            wrap: false,
+            scrolloff: 5,
+            sidescrolloff: 5,
+            number: true,
+            relativenumber: false,
+            signcolumn: true,
+            cursorline: false,
+            debug: false,
+        }
+    }
+}
+
+impl Options {
+    /// Applies a single `:set` argument: `name=value`, `name` or `noname`.
+    pub fn set(&mut self, arg: &str) -> Result<(), String> {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (arg.trim(), None),
+        };
+
+        match (name, value) {
+            ("tabstop" | "ts", Some(v)) => self.tabstop = parse_width(name, v)?,
+            ("shiftwidth" | "sw", Some(v)) => self.shiftwidth = parse_width(name, v)?,
+            ("expandtab" | "et", None) => self.expandtab = true,
+            ("noexpandtab" | "noet", None) => self.expandtab = false,
+            ("autopairs", None) => self.autopairs = true,
+            ("noautopairs", None) => self.autopairs = false,
+            ("formatonsave", None) => self.formatonsave = true,
+            ("noformatonsave", None) => self.formatonsave = false,
+            ("trimtrailing", None) => self.trimtrailing = true,
+            ("notrimtrailing", None) => self.trimtrailing = false,
+            ("fixeol", None) => self.fixeol = true,
+            ("nofixeol", None) => self.fixeol = false,
+            ("retab", None) => self.retab = true,
+            ("noretab", None) => self.retab = false,
+            ("scrolloff" | "so", Some(v)) => self.scrolloff = parse_count(name, v)?,
+            ("sidescrolloff" | "siso", Some(v)) => self.sidescrolloff = parse_count(name, v)?,
+            ("number" | "nu", None) => self.number = true,
+            ("nonumber" | "nonu", None) => self.number = false,
+            ("relativenumber" | "rnu", None) => self.relativenumber = true,
+            ("norelativenumber" | "nornu", None) => self.relativenumber = false,
+            ("signcolumn" | "scl", None) => self.signcolumn = true,
+            ("nosigncolumn" | "noscl", None) => self.signcolumn = false,
+            ("cursorline" | "cul", None) => self.cursorline = true,
+            ("nocursorline" | "nocul", None) => self.cursorline = false,
+            ("debug", None) => self.debug = true,
+            ("nodebug", None) => self.debug = false,
+            ("wrap", None) => self.wrap = true,
+            ("nowrap", None) => self.wrap = false,
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+
+        Ok(())
+    }
+
+    /// The whitespace inserted for one level of indentation.
+    pub fn indent_unit(&self) -> String {
+        if self.expandtab {
+            " ".repeat(self.shiftwidth)
+        } else {
+            "\t".to_string()
+        }
+    }
+
+    /// Leading whitespace spanning `width` display columns, using tabs where
+    /// possible unless `expandtab` is set.
+    pub fn indent_string(&self, width: usize) -> String {
+        if self.expandtab {
+            " ".repeat(width)
+        } else {
+            let mut s = "\t".repeat(width / self.tabstop);
+            s.push_str(&" ".repeat(width % self.tabstop));
+            s
+        }
+    }
+}
+
+fn parse_width(name: &str, value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err(format!("Invalid value for {}: {}", name, value)),
+    }
+}
+
+fn parse_count(name: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid value for {}: {}", name, value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_parses_values_and_flags() {
+        let mut o = Options::default();
+        o.set("ts=4").unwrap();
+        o.set("shiftwidth=2").unwrap();
+        o.set("noet").unwrap();
+
+        assert_eq!(o.tabstop, 4);
+        assert_eq!(o.shiftwidth, 2);
+        assert!(!o.expandtab);
+        assert_eq!(o.indent_unit(), "\t");
+
+        o.set("expandtab").unwrap();
+        assert_eq!(o.indent_unit(), "  ");
+    }
+
+    #[test]
+    fn indent_string_mixes_tabs_and_spaces_without_expandtab() {
+        let mut o = Options::default();
+        o.set("ts=4").unwrap();
+        o.set("noet").unwrap();
+        assert_eq!(o.indent_string(10), "\t\t  ");
+
+        o.set("et").unwrap();
+        assert_eq!(o.indent_string(3), "   ");
+    }
+
+    #[test]
+    fn set_rejects_bad_input() {
+        let mut o = Options::default();
+        assert!(o.set("tabstop=0").is_err());
+        assert!(o.set("tabstop=x").is_err());
+        assert!(o.set("siso=0").is_ok());
+        assert!(o.set("siso=-1").is_err());
+        assert!(o.set("frobnicate").is_err());
+    }
+}
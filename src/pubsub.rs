@@ -65,6 +65,12 @@ struct HubInternal {
     topics: HashMap<TopicIdInternal, Box<dyn Any + Send>>,
 }
 
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Hub {
     pub fn new() -> Self {
         Hub {
@@ -72,6 +78,7 @@ impl Hub {
         }
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn send<T: 'static + Clone + Send>(
         &mut self,
         topic: TopicId<T>,
@@ -101,19 +108,19 @@ impl HubInternal {
         let mut closed_channels = Vec::new();
         for (i, s) in t.senders.iter().enumerate() {
             let result = s.send(value.clone()).map_err(|_| ());
-            if let Err(_) = result {
+            if result.is_err() {
                 closed_channels.push(i);
             }
         }
 
-        if closed_channels.len() > 0 {
+        if !closed_channels.is_empty() {
             log::debug!("Cleaning closed channels for topic: {}", topic);
         }
         for closed in closed_channels.iter().rev() {
             t.senders.swap_remove(*closed);
         }
 
-        if t.senders.len() > 0 {
+        if !t.senders.is_empty() {
             Ok(())
         } else {
             Err(())
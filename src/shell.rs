@@ -0,0 +1,146 @@
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Runs `command` with `sh -c`, feeding it `input` on stdin. Returns what it
+/// wrote to stdout, or a message including its stderr if it could not be run
+/// or exited unsuccessfully.
+pub fn run(command: &str, input: Option<String>) -> Result<String, String> {
+    run_with_timeout(command, input, None)
+}
+
+/// Like `run`, but kills the command and fails if it hasn't finished within
+/// `timeout`.
+pub fn run_with_timeout(
+    command: &str,
+    input: Option<String>,
+    timeout: Option<Duration>,
+) -> Result<String, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        // its own process group, so that a timeout kills anything it started
+        .process_group(0)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", command, e))?;
+
+    // Written from another thread so a command producing lots of output
+    // can't block us while we're still writing its input.
+    let writer = child.stdin.take().zip(input).map(|(mut stdin, input)| {
+        thread::spawn(move || {
+            // a command that exits without reading its input is not an error
+            let _ = stdin.write_all(input.as_bytes());
+        })
+    });
+
+    let stderr_reader = child.stderr.take().map(|mut err| {
+        thread::spawn(move || {
+            let mut stderr = String::new();
+            let _ = err.read_to_string(&mut stderr);
+            stderr
+        })
+    });
+    let stdout_reader = child.stdout.take().map(|mut out| {
+        thread::spawn(move || {
+            let mut stdout = Vec::new();
+            let _ = out.read_to_end(&mut stdout);
+            stdout
+        })
+    });
+
+    let status =
+        match wait(&mut child, timeout).map_err(|e| format!("Failed to run {}: {}", command, e))? {
+            Some(status) => status,
+            None => {
+                // safe: kill only sends a signal, to the group the child leads
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                let _ = child.wait();
+                return Err(format!(
+                    "{} timed out after {}s",
+                    command,
+                    timeout.unwrap_or_default().as_secs_f32()
+                ));
+            }
+        };
+    let stdout = stdout_reader
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    let stderr = stderr_reader
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+
+    if status.success() {
+        Ok(String::from_utf8_lossy(&stdout).into_owned())
+    } else {
+        let stderr = stderr.trim();
+        Err(if stderr.is_empty() {
+            format!("{} failed ({})", command, status)
+        } else {
+            format!("{} failed ({}): {}", command, status, stderr)
+        })
+    }
+}
+
+/// Waits for `child` to exit, giving up with `None` after `timeout`.
+fn wait(child: &mut Child, timeout: Option<Duration>) -> io::Result<Option<ExitStatus>> {
+    let deadline = match timeout {
+        Some(timeout) => Instant::now() + timeout,
+        None => return child.wait().map(Some),
+    };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pipes_input_through_command() {
+        assert_eq!(
+            run("sort", Some("b\na\n".to_string())),
+            Ok("a\nb\n".to_string())
+        );
+        assert_eq!(run("echo hi", None), Ok("hi\n".to_string()));
+    }
+
+    #[test]
+    fn commands_are_killed_after_a_timeout() {
+        let start = Instant::now();
+        let err = run_with_timeout("sleep 5; echo late", None, Some(Duration::from_millis(100)))
+            .unwrap_err();
+        assert!(err.contains("timed out"), "{}", err);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let quick = Some(Duration::from_secs(5));
+        assert_eq!(
+            run_with_timeout("echo hi", None, quick),
+            Ok("hi\n".to_string())
+        );
+    }
+
+    #[test]
+    fn failures_include_stderr() {
+        let err = run("echo oops >&2; exit 3", None).unwrap_err();
+        assert!(err.contains("oops"), "{}", err);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::pubsub;
+use crate::text::LineId;
+use std::collections::HashMap;
+
+/// What a sign marks, which decides how it's drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignKind {
+    Error,
+    Warning,
+    Added,
+    Modified,
+    Removed,
+    Bookmark,
+}
+
+impl SignKind {
+    /// The two columns drawn for this kind of sign.
+    pub fn text(&self) -> &'static str {
+        match self {
+            SignKind::Error => "E>",
+            SignKind::Warning => "W>",
+            SignKind::Added => "+ ",
+            SignKind::Modified => "~ ",
+            SignKind::Removed => "_ ",
+            SignKind::Bookmark => "# ",
+        }
+    }
+
+    fn default_priority(&self) -> u8 {
+        match self {
+            SignKind::Error => 40,
+            SignKind::Warning => 30,
+            SignKind::Bookmark => 20,
+            SignKind::Added | SignKind::Modified | SignKind::Removed => 10,
+        }
+    }
+}
+
+/// A mark shown in the sign column next to a line. When several signs
+/// target one line, the one with the highest priority is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sign {
+    kind: SignKind,
+    priority: u8,
+}
+
+impl Sign {
+    pub fn new(kind: SignKind) -> Self {
+        Sign {
+            kind,
+            priority: kind.default_priority(),
+        }
+    }
+
+    pub fn with_priority(self, priority: u8) -> Self {
+        Sign { priority, ..self }
+    }
+
+    pub fn kind(&self) -> SignKind {
+        self.kind
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+/// Every sign placed by one source, e.g. diagnostics or version control.
+/// Each update replaces all of that source's earlier signs.
+#[derive(Debug, Clone)]
+pub struct SignUpdate {
+    source: &'static str,
+    signs: HashMap<LineId, Sign>,
+}
+
+impl SignUpdate {
+    pub fn new(source: &'static str, signs: HashMap<LineId, Sign>) -> Self {
+        SignUpdate { source, signs }
+    }
+}
+
+pub fn topic() -> pubsub::TopicId<SignUpdate> {
+    pubsub::typed_topic("signs")
+}
+
+/// The signs placed by every source. Signs are keyed by line id, so they
+/// follow their lines as text is edited around them.
+#[derive(Debug, Default)]
+pub struct SignColumn {
+    by_source: HashMap<&'static str, HashMap<LineId, Sign>>,
+}
+
+impl SignColumn {
+    pub fn update(&mut self, update: SignUpdate) {
+        if update.signs.is_empty() {
+            self.by_source.remove(update.source);
+        } else {
+            self.by_source.insert(update.source, update.signs);
+        }
+    }
+
+    /// The sign to show next to `line`, if any.
+    pub fn sign_for(&self, line: LineId) -> Option<Sign> {
+        self.by_source
+            .values()
+            .filter_map(|signs| signs.get(&line))
+            .max_by_key(|sign| sign.priority)
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text::Text;
+
+    #[test]
+    fn highest_priority_sign_wins() {
+        let text = Text::from(&["a".to_string(), "b".to_string()]);
+        let ids: Vec<LineId> = text.iter_lines().map(|l| l.id()).collect();
+        let mut column = SignColumn::default();
+
+        let vcs = HashMap::from([(ids[0], Sign::new(SignKind::Added))]);
+        column.update(SignUpdate::new("vcs", vcs));
+        let diagnostics = HashMap::from([
+            (ids[0], Sign::new(SignKind::Warning)),
+            (ids[1], Sign::new(SignKind::Error).with_priority(1)),
+        ]);
+        column.update(SignUpdate::new("diagnostics", diagnostics));
+
+        assert_eq!(
+            column.sign_for(ids[0]).map(|s| s.kind()),
+            Some(SignKind::Warning)
+        );
+        assert_eq!(
+            column.sign_for(ids[1]).map(|s| s.kind()),
+            Some(SignKind::Error)
+        );
+
+        let vcs = HashMap::from([(ids[1], Sign::new(SignKind::Modified))]);
+        column.update(SignUpdate::new("vcs", vcs));
+        assert_eq!(
+            column.sign_for(ids[1]).map(|s| s.kind()),
+            Some(SignKind::Modified)
+        );
+
+        column.update(SignUpdate::new("diagnostics", HashMap::new()));
+        assert_eq!(column.sign_for(ids[0]), None);
+    }
+}
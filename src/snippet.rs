@@ -0,0 +1,498 @@
+use std::fs;
+use std::io;
+
+use serde_json::Value;
+
+use crate::config;
+use crate::language::Language;
+
+#[derive(Debug, Clone)]
+pub struct Snippet {
+    name: String,
+    prefix: String,
+    body: String,
+}
+
+impl Snippet {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+}
+
+/// The text a snippet expands to, along with the tab stops within it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub text: String,
+    pub fields: Vec<Field>,
+}
+
+/// A tab stop in an expansion. Several fields with the same number mirror
+/// one another; `offset` and `len` count characters of the expansion text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub number: u32,
+    pub offset: usize,
+    pub len: usize,
+    pub choices: Vec<String>,
+}
+
+/// Loads the snippets for `language` from `<config dir>/snippets/<name>.json`,
+/// in the format used by TextMate and VS Code snippet files.
+pub fn load(language: &Language) -> Result<Vec<Snippet>, String> {
+    let path = match config::config_dir() {
+        Some(dir) => dir
+            .join("snippets")
+            .join(format!("{}.json", language.name())),
+        None => return Ok(Vec::new()),
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(json) => parse_snippets(&json).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+pub fn parse_snippets(json: &str) -> Result<Vec<Snippet>, String> {
+    let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let entries = value
+        .as_object()
+        .ok_or_else(|| "expected an object of snippets".to_string())?;
+
+    let mut snippets = Vec::new();
+    for (name, entry) in entries {
+        let body = match entry.get("body") {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Array(lines)) => lines
+                .iter()
+                .map(|l| l.as_str().unwrap_or(""))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => return Err(format!("snippet {} has no body", name)),
+        };
+        let prefixes = match entry.get("prefix") {
+            Some(Value::String(s)) => vec![s.clone()],
+            Some(Value::Array(ps)) => ps
+                .iter()
+                .filter_map(|p| p.as_str().map(String::from))
+                .collect(),
+            _ => return Err(format!("snippet {} has no prefix", name)),
+        };
+        for prefix in prefixes {
+            snippets.push(Snippet {
+                name: name.clone(),
+                prefix,
+                body: body.clone(),
+            });
+        }
+    }
+
+    Ok(snippets)
+}
+
+/// Finds the snippet triggered by the text before the cursor: the one with
+/// the longest prefix that the text ends with, starting on a word boundary.
+pub fn find_trigger<'a>(snippets: &'a [Snippet], before_cursor: &str) -> Option<&'a Snippet> {
+    snippets
+        .iter()
+        .filter(|s| !s.prefix.is_empty())
+        .filter(|s| match before_cursor.strip_suffix(s.prefix.as_str()) {
+            Some(rest) => rest
+                .chars()
+                .last()
+                .map(|c| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(true),
+            None => false,
+        })
+        .max_by_key(|s| s.prefix.len())
+}
+
+/// Expands a snippet body, resolving `$VARIABLES` with `variable`. The final
+/// tab stop `$0` is added at the end of the expansion if the body has none.
+pub fn expand(body: &str, variable: &dyn Fn(&str) -> Option<String>) -> Expansion {
+    let mut parser = Parser {
+        chars: body.chars().collect(),
+        pos: 0,
+        out: Vec::new(),
+        fields: Vec::new(),
+        variable,
+    };
+    parser.parse_until_close(false);
+
+    fill_mirrors(&mut parser.out, &mut parser.fields);
+
+    if !parser.fields.iter().any(|f| f.number == 0) {
+        parser.fields.push(Field {
+            number: 0,
+            offset: parser.out.len(),
+            len: 0,
+            choices: Vec::new(),
+        });
+    }
+
+    Expansion {
+        text: parser.out.into_iter().collect(),
+        fields: parser.fields,
+    }
+}
+
+/// Copies the placeholder of the first field with each number into the empty
+/// fields that mirror it.
+fn fill_mirrors(out: &mut Vec<char>, fields: &mut [Field]) {
+    for m in 0..fields.len() {
+        let placeholder: Vec<char> = match fields.iter().find(|f| f.number == fields[m].number) {
+            Some(primary) if fields[m].len == 0 && primary.len > 0 => {
+                out[primary.offset..primary.offset + primary.len].to_vec()
+            }
+            _ => continue,
+        };
+
+        let offset = fields[m].offset;
+        let added = placeholder.len();
+        out.splice(offset..offset, placeholder);
+        for (j, f) in fields.iter_mut().enumerate() {
+            if f.offset > offset || (f.offset == offset && j > m) {
+                f.offset += added;
+            } else if f.offset < offset && f.offset + f.len >= offset && j != m {
+                f.len += added;
+            }
+        }
+        fields[m].len = added;
+    }
+}
+
+/// Splits an expansion into lines for insertion, indenting every line after
+/// the first with `indent` and replacing tabs with `tab`. Returns the lines
+/// and the `(line, column, len)` of each field.
+pub fn layout(
+    expansion: &Expansion,
+    indent: &str,
+    tab: &str,
+) -> (Vec<String>, Vec<(usize, usize, usize)>) {
+    let mut lines = vec![String::new()];
+    let mut positions = Vec::with_capacity(expansion.text.len() + 1);
+    let mut col = 0;
+
+    for c in expansion.text.chars() {
+        positions.push((lines.len() - 1, col));
+        let line = lines.last_mut().unwrap();
+        match c {
+            '\n' => {
+                lines.push(indent.to_string());
+                col = indent.chars().count();
+            }
+            '\t' => {
+                line.push_str(tab);
+                col += tab.chars().count();
+            }
+            c => {
+                line.push(c);
+                col += 1;
+            }
+        }
+    }
+    positions.push((lines.len() - 1, col));
+
+    let fields = expansion
+        .fields
+        .iter()
+        .map(|f| {
+            let (line, start) = positions[f.offset];
+            let (end_line, end) = positions[f.offset + f.len];
+            let len = if end_line == line { end - start } else { 0 };
+            (line, start, len)
+        })
+        .collect();
+
+    (lines, fields)
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    out: Vec<char>,
+    fields: Vec<Field>,
+    variable: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn parse_until_close(&mut self, nested: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                '}' if nested => {
+                    self.pos += 1;
+                    return;
+                }
+                '\\' => {
+                    match self.chars.get(self.pos + 1) {
+                        Some(escaped @ ('$' | '}' | '\\')) => {
+                            self.out.push(*escaped);
+                            self.pos += 2;
+                        }
+                        _ => {
+                            self.out.push(c);
+                            self.pos += 1;
+                        }
+                    };
+                }
+                '$' => self.parse_dollar(),
+                _ => {
+                    self.out.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn parse_dollar(&mut self) {
+        let start = self.pos;
+        self.pos += 1;
+
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => {
+                let number = self.number();
+                self.push_field(number, self.out.len(), Vec::new());
+            }
+            Some(c) if is_variable_start(c) => {
+                let name = self.variable_name();
+                let value = (self.variable)(&name).unwrap_or_default();
+                self.out.extend(value.chars());
+            }
+            Some('{') => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(c) if c.is_ascii_digit() => self.parse_braced_field(),
+                    Some(c) if is_variable_start(c) => self.parse_braced_variable(),
+                    _ => self.literal_from(start),
+                }
+            }
+            _ => self.out.push('$'),
+        }
+    }
+
+    fn parse_braced_field(&mut self) {
+        let number = self.number();
+        let offset = self.out.len();
+        match self.peek() {
+            Some('}') => {
+                self.pos += 1;
+                self.push_field(number, offset, Vec::new());
+            }
+            Some(':') => {
+                self.pos += 1;
+                let field_index = self.fields.len();
+                self.fields.push(Field {
+                    number,
+                    offset,
+                    len: 0,
+                    choices: Vec::new(),
+                });
+                self.parse_until_close(true);
+                self.fields[field_index].len = self.out.len() - offset;
+            }
+            Some('|') => {
+                self.pos += 1;
+                let choices = self.choices();
+                if let Some(first) = choices.first() {
+                    self.out.extend(first.chars());
+                }
+                self.push_field(number, offset, choices);
+            }
+            _ => self.push_field(number, offset, Vec::new()),
+        }
+    }
+
+    fn parse_braced_variable(&mut self) {
+        let name = self.variable_name();
+        let value = (self.variable)(&name);
+        match self.peek() {
+            Some(':') => {
+                self.pos += 1;
+                let out_len = self.out.len();
+                let fields_len = self.fields.len();
+                self.parse_until_close(true);
+                if let Some(value) = value {
+                    self.out.truncate(out_len);
+                    self.fields.truncate(fields_len);
+                    self.out.extend(value.chars());
+                }
+            }
+            _ => {
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                }
+                self.out.extend(value.unwrap_or_default().chars());
+            }
+        }
+    }
+
+    fn choices(&mut self) -> Vec<String> {
+        let mut choices = Vec::new();
+        let mut current = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '\\' => {
+                    if let Some(escaped) = self.peek() {
+                        current.push(escaped);
+                        self.pos += 1;
+                    }
+                }
+                ',' => choices.push(std::mem::take(&mut current)),
+                '|' if self.peek() == Some('}') => {
+                    self.pos += 1;
+                    break;
+                }
+                c => current.push(c),
+            }
+        }
+        choices.push(current);
+        choices
+    }
+
+    fn number(&mut self) -> u32 {
+        let mut n = 0u32;
+        while let Some(d) = self.peek().and_then(|c| c.to_digit(10)) {
+            n = n.saturating_mul(10).saturating_add(d);
+            self.pos += 1;
+        }
+        n
+    }
+
+    fn variable_name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_alphanumeric() || *c == '_') {
+            name.push(c);
+            self.pos += 1;
+        }
+        name
+    }
+
+    fn push_field(&mut self, number: u32, offset: usize, choices: Vec<String>) {
+        let len = self.out.len() - offset;
+        self.fields.push(Field {
+            number,
+            offset,
+            len,
+            choices,
+        });
+    }
+
+    fn literal_from(&mut self, start: usize) {
+        self.out.extend_from_slice(&self.chars[start..self.pos]);
+    }
+}
+
+fn is_variable_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn no_variables(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn expands_placeholders_and_adds_final_stop() {
+        let e = expand("fn ${1:name}($2) {}", &no_variables);
+        assert_eq!(e.text, "fn name() {}");
+        assert_eq!(
+            e.fields,
+            vec![
+                Field {
+                    number: 1,
+                    offset: 3,
+                    len: 4,
+                    choices: vec![]
+                },
+                Field {
+                    number: 2,
+                    offset: 8,
+                    len: 0,
+                    choices: vec![]
+                },
+                Field {
+                    number: 0,
+                    offset: 12,
+                    len: 0,
+                    choices: vec![]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_mirrors_choices_and_escapes() {
+        let e = expand("${1|a,b\\,c|} \\$1 $1$0", &no_variables);
+        assert_eq!(e.text, "a $1 a");
+        assert_eq!(e.fields[0].choices, vec!["a", "b,c"]);
+        assert_eq!(e.fields[1].number, 1);
+        assert_eq!(e.fields[1].offset, 5);
+        assert_eq!(e.fields[1].len, 1);
+        assert_eq!(e.fields[2].offset, 6);
+        assert_eq!(e.fields.len(), 3);
+    }
+
+    #[test]
+    fn expands_variables_with_defaults() {
+        let vars = |name: &str| match name {
+            "TM_FILENAME" => Some("main.rs".to_string()),
+            _ => None,
+        };
+        let e = expand(
+            "$TM_FILENAME ${TM_SELECTED_TEXT:${1:x}} ${TM_FILENAME:y}",
+            &vars,
+        );
+        assert_eq!(e.text, "main.rs x main.rs");
+        assert_eq!(e.fields[0].offset, 8);
+        assert_eq!(e.fields[0].len, 1);
+    }
+
+    #[test]
+    fn layout_indents_continuation_lines() {
+        let e = expand("if $1 {\n\t${2:body}\n}", &no_variables);
+        let (lines, fields) = layout(&e, "  ", "    ");
+        assert_eq!(lines, vec!["if  {", "      body", "  }"]);
+        assert_eq!(fields, vec![(0, 3, 0), (1, 6, 4), (2, 3, 0)]);
+    }
+
+    #[test]
+    fn parses_vscode_snippet_files() {
+        let snippets = parse_snippets(
+            r#"{
+                "Function": { "prefix": ["fn", "func"], "body": ["fn $1() {", "\t$0", "}"] },
+                "Print": { "prefix": "pr", "body": "println!(\"$1\");" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(snippets.len(), 3);
+        let f = snippets.iter().find(|s| s.prefix() == "func").unwrap();
+        assert_eq!(f.body(), "fn $1() {\n\t$0\n}");
+    }
+
+    #[test]
+    fn trigger_must_start_on_word_boundary() {
+        let snippets = parse_snippets(
+            r#"{ "a": { "prefix": "fn", "body": "" }, "b": { "prefix": "pfn", "body": "" } }"#,
+        )
+        .unwrap();
+        assert_eq!(find_trigger(&snippets, "    fn").unwrap().name(), "a");
+        assert_eq!(find_trigger(&snippets, "x.pfn").unwrap().name(), "b");
+        assert!(find_trigger(&snippets, "xfn").is_none());
+    }
+}
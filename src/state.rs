@@ -1,17 +1,68 @@
-use crate::userinput::{Event, Key};
+use crate::columns;
+use crate::comment;
+use crate::excommand::ExCommand;
+use crate::gutter;
+use crate::highlight::HighlightState;
+use crate::language::{self, Language};
+use crate::layout::{self, Layout, Orientation, Side, WindowId};
+use crate::options::Options;
+use crate::shell;
+use crate::signs::{self, Sign, SignKind, SignUpdate};
+use crate::snippet::{self, Snippet};
+use crate::text::{Case, LineId, Rev};
+use crate::theme;
+use crate::userinput::{Event, Key, MouseButton, MouseEvent};
+use crate::wrap;
 use crate::{
     pubsub::{self, Hub},
     text::{Text, TextView},
 };
-use std::ffi::OsStr;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
 use std::fs::OpenOptions;
+use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::{fs::File, usize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use unicode_width::UnicodeWidthStr;
 
-pub fn text_update_topic() -> pubsub::TopicId<TextView> {
+/// How long a formatter can take before the save is abandoned
+const FORMAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn text_update_topic() -> pubsub::TopicId<TextUpdate> {
     pubsub::typed_topic("body-text")
 }
 
+#[derive(Clone)]
+pub struct TextUpdate {
+    buffer: usize,
+    text: TextView,
+    language: &'static Language,
+}
+
+impl TextUpdate {
+    /// The number of the buffer the text belongs to.
+    pub fn buffer(&self) -> usize {
+        self.buffer
+    }
+
+    pub fn text(&self) -> &TextView {
+        &self.text
+    }
+
+    pub fn language(&self) -> &'static Language {
+        self.language
+    }
+}
+
+/// Sent with a buffer's number when it's deleted, so caches of its lines can
+/// be dropped.
+pub fn buffer_deleted_topic() -> pubsub::TopicId<usize> {
+    pubsub::typed_topic("buffer-deleted")
+}
+
 pub fn state_update_topic() -> pubsub::TopicId<StateSnapshot> {
     pubsub::typed_topic("state")
 }
@@ -29,6 +80,99 @@ pub struct StateSnapshot {
     status_text: String,
     mode: Mode,
     command_line: String,
+    options: Options,
+    layout: Layout,
+    windows: Vec<WindowView>,
+    tabs: Vec<TabView>,
+}
+
+/// A tab page's entry in the tab bar.
+#[derive(Clone)]
+pub struct TabView {
+    name: String,
+    modified: bool,
+    current: bool,
+}
+
+impl TabView {
+    /// The name of the file in the tab's focused window.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether any buffer shown in the tab has unsaved changes.
+    pub fn modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn current(&self) -> bool {
+        self.current
+    }
+
+    /// What the tab bar shows for the tab at `index`.
+    pub fn label(&self, index: usize) -> String {
+        format!(
+            " {} {}{} ",
+            index + 1,
+            self.name,
+            if self.modified { " +" } else { "" }
+        )
+    }
+}
+
+/// What a window is showing.
+#[derive(Clone)]
+pub struct WindowView {
+    id: WindowId,
+    text: TextView,
+    cursor_pos: CursorPos,
+    top_line: usize,
+    left_col: usize,
+    selection: Option<(CursorPos, CursorPos)>,
+    buffer_name: String,
+    modified: bool,
+    focused: bool,
+}
+
+impl WindowView {
+    pub fn id(&self) -> WindowId {
+        self.id
+    }
+
+    pub fn text(&self) -> &TextView {
+        &self.text
+    }
+
+    pub fn cursor_pos(&self) -> &CursorPos {
+        &self.cursor_pos
+    }
+
+    /// The first line the window shows, if the cursor is in view from it.
+    pub fn top_line(&self) -> usize {
+        self.top_line
+    }
+
+    /// The first display column the window shows when lines aren't wrapped.
+    pub fn left_col(&self) -> usize {
+        self.left_col
+    }
+
+    /// The first and last positions of the selected text, if any.
+    pub fn selection(&self) -> Option<&(CursorPos, CursorPos)> {
+        self.selection.as_ref()
+    }
+
+    pub fn buffer_name(&self) -> &str {
+        &self.buffer_name
+    }
+
+    pub fn modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn focused(&self) -> bool {
+        self.focused
+    }
 }
 
 impl StateSnapshot {
@@ -51,18 +195,195 @@ impl StateSnapshot {
     pub fn status_text(&self) -> &str {
         &self.status_text
     }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn window(&self, id: WindowId) -> Option<&WindowView> {
+        self.windows.iter().find(|w| w.id == id)
+    }
+
+    pub fn tabs(&self) -> &[TabView] {
+        &self.tabs
+    }
 }
 
 pub struct State {
-    cursor_pos: CursorPos,
-    text: Text,
+    buffer: Buffer,
+    /// Open buffers other than the current one, ordered by number
+    hidden_buffers: Vec<Buffer>,
+    next_buffer_number: usize,
+    layout: Layout,
+    windows: Vec<Window>,
+    /// The window showing `buffer`, whose cursor is the buffer's
+    focused_window: WindowId,
+    next_window_id: WindowId,
+    /// Every tab page, though the current one's entry is only brought up to
+    /// date when switching away from it
+    tabs: Vec<TabPage>,
+    current_tab: usize,
+    screen_size: (u16, u16),
+    /// Where a selection made with the mouse started. The cursor is its
+    /// other end.
+    selection_anchor: Option<CursorPos>,
     status_text: String,
     mode: Mode,
     command_line: String,
-    file: Option<File>,
+    options: Options,
+    /// The name of the colour scheme in use
+    colorscheme: String,
     pubsub: Hub,
 }
 
+#[derive(Clone)]
+struct TabPage {
+    layout: Layout,
+    windows: Vec<Window>,
+    focused_window: WindowId,
+}
+
+/// A view onto a buffer. The focused window's cursor is kept in its buffer
+/// while it has focus.
+#[derive(Clone)]
+struct Window {
+    id: WindowId,
+    buffer: usize,
+    cursor_pos: CursorPos,
+    /// The first line shown
+    top_line: usize,
+    /// The first display column shown, when lines aren't wrapped
+    left_col: usize,
+}
+
+/// A file being edited, and everything about it that's kept while other
+/// buffers are shown.
+struct Buffer {
+    number: usize,
+    cursor_pos: CursorPos,
+    text: Text,
+    file: Option<File>,
+    path: Option<PathBuf>,
+    /// The text's revision when it was last loaded or saved
+    saved_rev: Rev,
+    language: &'static Language,
+    highlight: Option<HighlightState>,
+    snippets: Vec<Snippet>,
+    snippet_session: Option<SnippetSession>,
+    bookmarks: HashSet<LineId>,
+}
+
+impl Buffer {
+    fn empty(number: usize) -> Buffer {
+        Buffer {
+            number,
+            cursor_pos: CursorPos {
+                line_number: 0,
+                colmun: 0,
+            },
+            text: Text::new(),
+            file: None,
+            path: None,
+            saved_rev: Rev::default(),
+            language: language::plain_text(),
+            highlight: None,
+            snippets: Vec::new(),
+            snippet_session: None,
+            bookmarks: HashSet::new(),
+        }
+    }
+
+    /// Opens (creating if needed) `fname`, also returning any problem
+    /// loading its snippets.
+    fn open(fname: &OsStr, number: usize) -> io::Result<(Buffer, String)> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(fname)?;
+        let reader = BufReader::new(f.try_clone()?);
+        let mut lines = Vec::new();
+
+        for l in reader.lines() {
+            let l = l?;
+            lines.push(l);
+        }
+
+        let language = language::for_path(Path::new(fname));
+        let (snippets, status_text) = match snippet::load(language) {
+            Ok(snippets) => (snippets, String::new()),
+            Err(e) => (Vec::new(), format!("Failed to load snippets: {}", e)),
+        };
+
+        let text = Text::from(&lines);
+        let buffer = Buffer {
+            saved_rev: text.rev(),
+            text,
+            file: Some(f),
+            path: Some(PathBuf::from(fname)),
+            language,
+            snippets,
+            ..Buffer::empty(number)
+        };
+        Ok((buffer, status_text))
+    }
+
+    fn is_modified(&self) -> bool {
+        self.text.rev() != self.saved_rev
+    }
+
+    /// Whether this buffer is editing `path`, however either was spelled.
+    fn is_editing(&self, path: &Path) -> bool {
+        match &self.path {
+            Some(own) if own == path => true,
+            Some(own) => match (fs::canonicalize(own), fs::canonicalize(path)) {
+                (Ok(own), Ok(path)) => own == path,
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    fn short_name(&self) -> String {
+        match self.path.as_ref().and_then(|p| p.file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => self.name(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => "[No Name]".to_string(),
+        }
+    }
+}
+
+/// Tab stops of an expanded snippet that's still being filled in.
+struct SnippetSession {
+    fields: Vec<SnippetField>,
+    order: Vec<u32>,
+    current: usize,
+    /// Typing replaces the current field's placeholder, until the field is edited
+    placeholder_pending: bool,
+    /// The length of the current field's line when the field was entered, so
+    /// that what has been typed into it can be measured from the text
+    line_len: usize,
+}
+
+struct SnippetField {
+    number: u32,
+    line: LineId,
+    col: usize,
+    len: usize,
+    choices: Vec<String>,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Mode {
     Insert,
@@ -72,6 +393,8 @@ pub enum Mode {
 
 pub enum EditorAction {
     Quit,
+    /// Hand the terminal back to the shell and stop until resumed
+    Suspend,
     None,
 }
 
@@ -85,14 +408,206 @@ pub enum Command {
         lines_down: isize,
         columns_right: isize,
     },
+    /// Move up or down by rows on screen rather than lines of text, which
+    /// differ when long lines are wrapped
+    MoveDisplayLine(isize),
+    ShiftLines(isize),
+    NextTabStop,
+    PrevTabStop,
+    CycleChoice,
+    LineOp(LineOp),
+    DeleteWordBackward,
+    Window(WindowCommand),
+    /// Go to the next tab page, or the previous one if false
+    NextTab(bool),
+    ToggleBookmark,
+    /// Scroll the view down (or up, if negative) by lines, only moving the
+    /// cursor if it would go out of view
+    Scroll(isize),
+    /// Scroll so the cursor line is at the given place in the window
+    Reposition(ViewAnchor),
+    Suspend,
+    /// The left mouse button was pressed at a (1-based) screen column and row
+    Click {
+        column: u16,
+        row: u16,
+    },
+    /// The mouse was moved to a screen cell with a button held down
+    Drag {
+        column: u16,
+        row: u16,
+    },
+    /// The mouse wheel was turned over a screen cell
+    Wheel {
+        column: u16,
+        row: u16,
+        lines: isize,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ViewAnchor {
+    Top,
+    Middle,
+    Bottom,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum WindowCommand {
+    Split(Orientation),
+    Close,
+    Focus(Side),
+    FocusNext,
+    Resize(Orientation, i32),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LineOp {
+    Delete,
+    Duplicate,
+    MoveUp,
+    MoveDown,
+    Join,
+    ChangeCase(Case),
+    Sort,
+    Reverse,
+    Unique,
+    ToggleComment,
+}
+
+enum KeySequence {
+    Complete(Command),
+    Partial,
+    Unbound,
+}
+
+fn normal_mode_command(keys: &[Key]) -> KeySequence {
+    use KeySequence::*;
+
+    match keys {
+        [Key::Char('u')] => Complete(Command::MoveCursor {
+            lines_down: -1,
+            columns_right: 0,
+        }),
+        [Key::Char('o')] => Complete(Command::MoveCursor {
+            lines_down: 0,
+            columns_right: 1,
+        }),
+        [Key::Char('e')] => Complete(Command::MoveCursor {
+            lines_down: 1,
+            columns_right: 0,
+        }),
+        [Key::Char('n')] => Complete(Command::MoveCursor {
+            lines_down: 0,
+            columns_right: -1,
+        }),
+        [Key::Char(':')] => Complete(Command::ShiftMode(Mode::Command)),
+        [Key::Char('i')] => Complete(Command::ShiftMode(Mode::Insert)),
+        [Key::Char('>')] | [Key::Char('<')] => Partial,
+        [Key::Char('>'), Key::Char('>')] => Complete(Command::ShiftLines(1)),
+        [Key::Char('<'), Key::Char('<')] => Complete(Command::ShiftLines(-1)),
+        [Key::Char('d')] | [Key::Char('y')] | [Key::Char('g')] | [Key::Char('m')] => Partial,
+        [Key::Char('m'), Key::Char('m')] => Complete(Command::ToggleBookmark),
+        [Key::Ctrl('z')] => Complete(Command::Suspend),
+        [Key::Ctrl('e')] => Complete(Command::Scroll(1)),
+        [Key::Ctrl('y')] => Complete(Command::Scroll(-1)),
+        [Key::Char('z')] => Partial,
+        [Key::Char('z'), Key::Char('t')] => Complete(Command::Reposition(ViewAnchor::Top)),
+        [Key::Char('z'), Key::Char('z')] => Complete(Command::Reposition(ViewAnchor::Middle)),
+        [Key::Char('z'), Key::Char('b')] => Complete(Command::Reposition(ViewAnchor::Bottom)),
+        [Key::Char('g'), Key::Char('c')] => Partial,
+        [Key::Char('g'), Key::Char('c'), Key::Char('c')] => {
+            Complete(Command::LineOp(LineOp::ToggleComment))
+        }
+        [Key::Char('d'), Key::Char('d')] => Complete(Command::LineOp(LineOp::Delete)),
+        [Key::Char('y'), Key::Char('p')] => Complete(Command::LineOp(LineOp::Duplicate)),
+        [Key::Alt('u')] => Complete(Command::LineOp(LineOp::MoveUp)),
+        [Key::Alt('e')] => Complete(Command::LineOp(LineOp::MoveDown)),
+        [Key::Ctrl('w')] => Partial,
+        [Key::Ctrl('w'), k] => match k {
+            Key::Char('s') => Complete(Command::Window(WindowCommand::Split(Orientation::Stacked))),
+            Key::Char('v') => Complete(Command::Window(WindowCommand::Split(
+                Orientation::SideBySide,
+            ))),
+            Key::Char('c') | Key::Char('q') => Complete(Command::Window(WindowCommand::Close)),
+            Key::Char('w') | Key::Ctrl('w') => Complete(Command::Window(WindowCommand::FocusNext)),
+            Key::Char('n') => Complete(Command::Window(WindowCommand::Focus(Side::Left))),
+            Key::Char('e') => Complete(Command::Window(WindowCommand::Focus(Side::Down))),
+            Key::Char('u') => Complete(Command::Window(WindowCommand::Focus(Side::Up))),
+            Key::Char('o') => Complete(Command::Window(WindowCommand::Focus(Side::Right))),
+            Key::Char('+') => Complete(Command::Window(WindowCommand::Resize(
+                Orientation::Stacked,
+                1,
+            ))),
+            Key::Char('-') => Complete(Command::Window(WindowCommand::Resize(
+                Orientation::Stacked,
+                -1,
+            ))),
+            Key::Char('>') => Complete(Command::Window(WindowCommand::Resize(
+                Orientation::SideBySide,
+                1,
+            ))),
+            Key::Char('<') => Complete(Command::Window(WindowCommand::Resize(
+                Orientation::SideBySide,
+                -1,
+            ))),
+            _ => Unbound,
+        },
+        [Key::Char('J')] => Complete(Command::LineOp(LineOp::Join)),
+        [Key::Char('g'), Key::Char('U')] => {
+            Complete(Command::LineOp(LineOp::ChangeCase(Case::Upper)))
+        }
+        [Key::Char('g'), Key::Char('u')] => {
+            Complete(Command::LineOp(LineOp::ChangeCase(Case::Lower)))
+        }
+        [Key::Char('g'), Key::Char('j')] => Complete(Command::MoveDisplayLine(1)),
+        [Key::Char('g'), Key::Char('k')] => Complete(Command::MoveDisplayLine(-1)),
+        [Key::Char('g'), Key::Char('t')] => Complete(Command::NextTab(true)),
+        [Key::Char('g'), Key::Char('T')] => Complete(Command::NextTab(false)),
+        [Key::Char('g'), Key::Char('~')] => {
+            Complete(Command::LineOp(LineOp::ChangeCase(Case::Toggle)))
+        }
+        _ => Unbound,
+    }
 }
 
-pub fn input_map(current_mode: &Mode, e: Event) -> Option<Command> {
+/// The number of lines a turn of the mouse wheel scrolls by
+const WHEEL_LINES: isize = 3;
+
+fn mouse_command(event: MouseEvent) -> Option<Command> {
+    match event {
+        MouseEvent::Press(MouseButton::Left, column, row) => Some(Command::Click { column, row }),
+        MouseEvent::Press(MouseButton::WheelUp, column, row) => Some(Command::Wheel {
+            column,
+            row,
+            lines: -WHEEL_LINES,
+        }),
+        MouseEvent::Press(MouseButton::WheelDown, column, row) => Some(Command::Wheel {
+            column,
+            row,
+            lines: WHEEL_LINES,
+        }),
+        MouseEvent::Hold(column, row) => Some(Command::Drag { column, row }),
+        _ => None,
+    }
+}
+
+/// Maps an input event to a command. Keys that only make sense as part of a
+/// longer sequence are collected in `pending` until the sequence is complete.
+pub fn input_map(current_mode: &Mode, pending: &mut Vec<Key>, e: Event) -> Option<Command> {
+    if let Event::Mouse(event) = e {
+        pending.clear();
+        return mouse_command(event);
+    }
     match current_mode {
         Mode::Insert => match e {
             Event::Key(k) => match k {
                 Key::Esc => Some(Command::ShiftMode(Mode::Normal)),
                 Key::Backspace => Some(Command::DeleteAtCursor),
+                Key::Char('\t') => Some(Command::NextTabStop),
+                Key::BackTab => Some(Command::PrevTabStop),
+                Key::Ctrl('n') => Some(Command::CycleChoice),
+                Key::Ctrl('w') => Some(Command::DeleteWordBackward),
                 Key::Char(c) => Some(Command::InsertAtCursor(c)),
                 _ => None,
             },
@@ -110,27 +625,24 @@ pub fn input_map(current_mode: &Mode, e: Event) -> Option<Command> {
             _ => None,
         },
         Mode::Normal => match e {
-            Event::Key(k) => match k {
-                Key::Char('u') => Some(Command::MoveCursor {
-                    lines_down: -1,
-                    columns_right: 0,
-                }),
-                Key::Char('o') => Some(Command::MoveCursor {
-                    lines_down: 0,
-                    columns_right: 1,
-                }),
-                Key::Char('e') => Some(Command::MoveCursor {
-                    lines_down: 1,
-                    columns_right: 0,
-                }),
-                Key::Char('n') => Some(Command::MoveCursor {
-                    lines_down: 0,
-                    columns_right: -1,
-                }),
-                Key::Char(':') => Some(Command::ShiftMode(Mode::Command)),
-                Key::Char('i') => Some(Command::ShiftMode(Mode::Insert)),
-                _ => None,
-            },
+            Event::Key(Key::Esc) => {
+                pending.clear();
+                None
+            }
+            Event::Key(k) => {
+                pending.push(k);
+                match normal_mode_command(pending) {
+                    KeySequence::Complete(c) => {
+                        pending.clear();
+                        Some(c)
+                    }
+                    KeySequence::Partial => None,
+                    KeySequence::Unbound => {
+                        pending.clear();
+                        None
+                    }
+                }
+            }
             _ => None,
         },
     }
@@ -140,6 +652,26 @@ impl<'a> State {
     pub fn dispatch(&'a mut self, c: Command) -> EditorAction {
         log::debug!("dispatching {:?} in mode {:?}", c, self.mode);
 
+        match c {
+            Command::Click { .. } | Command::Drag { .. } | Command::Wheel { .. } => {
+                if self.mode != Mode::Command {
+                    self.mouse(c);
+                }
+                return EditorAction::None;
+            }
+            _ => {}
+        }
+        // anything but the mouse ends a selection, after using it
+        let selection = self.selection();
+        self.selection_anchor = None;
+        let (first_line, last_line) = match &selection {
+            Some((start, end)) => (start.line_number, end.line_number),
+            None => (
+                self.buffer.cursor_pos.line_number,
+                self.buffer.cursor_pos.line_number,
+            ),
+        };
+
         if let Command::ShiftMode(m) = c {
             self.shift_mode(m);
             self.notify_change();
@@ -150,6 +682,17 @@ impl<'a> State {
             Mode::Insert => match c {
                 Command::DeleteAtCursor => self.delete(),
                 Command::InsertAtCursor(c) => self.insert(c),
+                Command::NextTabStop => self.next_tab_stop(),
+                Command::PrevTabStop => self.jump_tab_stop(-1),
+                Command::CycleChoice => self.cycle_choice(),
+                Command::DeleteWordBackward => {
+                    let ln = self.buffer.cursor_pos.line_number;
+                    self.buffer.cursor_pos.colmun = self
+                        .buffer
+                        .text
+                        .delete_word_before(ln, self.buffer.cursor_pos.colmun);
+                    self.notify_text_change();
+                }
                 _ => {}
             },
             Mode::Command => match c {
@@ -163,6 +706,20 @@ impl<'a> State {
                     lines_down,
                     columns_right,
                 } => self.move_cursor((lines_down, columns_right)),
+                Command::MoveDisplayLine(rows) => self.move_display_line(rows),
+                Command::ShiftLines(levels) => self.shift_lines(first_line, last_line, levels),
+                Command::LineOp(op) => self.line_op(first_line, last_line, op),
+                Command::NextTab(forward) => self.cycle_tab(forward),
+                Command::ToggleBookmark => self.toggle_bookmark(),
+                Command::Scroll(lines) => self.scroll_view(lines),
+                Command::Reposition(anchor) => self.reposition(anchor),
+                Command::Suspend => return EditorAction::Suspend,
+                Command::Window(command) => {
+                    if let Err(e) = self.window_command(command) {
+                        self.status_text = e;
+                        self.notify_change();
+                    }
+                }
                 _ => {}
             },
         };
@@ -171,39 +728,166 @@ impl<'a> State {
     }
 
     fn notify_change(&mut self) {
-        if let Err(_) = self.pubsub.send(
-            state_update_topic(),
-            StateSnapshot {
-                cursor_pos: self.cursor_pos.clone(),
-                text: self.text.view(),
-                status_text: self.status_text.clone(),
-                mode: self.mode.clone(),
-                command_line: self.command_line.clone(),
-            },
-        ) {
+        self.scroll_to_cursor();
+        if self
+            .pubsub
+            .send(
+                state_update_topic(),
+                StateSnapshot {
+                    cursor_pos: self.buffer.cursor_pos.clone(),
+                    text: self.buffer.text.view(),
+                    status_text: self.status_text.clone(),
+                    mode: self.mode.clone(),
+                    command_line: self.command_line.clone(),
+                    options: self.options.clone(),
+                    layout: self.layout.clone(),
+                    windows: self.window_views(),
+                    tabs: self.tab_views(),
+                },
+            )
+            .is_err()
+        {
             log::debug!("State changed but nobody's listening");
         }
     }
 
+    /// Bookmarks or un-bookmarks the cursor line, showing a sign next to it.
+    fn toggle_bookmark(&mut self) {
+        let line = match self.buffer.text.line(self.buffer.cursor_pos.line_number) {
+            Some(line) => line.id(),
+            None => return,
+        };
+        if !self.buffer.bookmarks.remove(&line) {
+            self.buffer.bookmarks.insert(line);
+        }
+
+        let marks = std::iter::once(&self.buffer)
+            .chain(&self.hidden_buffers)
+            .flat_map(|b| &b.bookmarks)
+            .map(|line| (*line, Sign::new(SignKind::Bookmark)))
+            .collect();
+        if self
+            .pubsub
+            .send(signs::topic(), SignUpdate::new("bookmarks", marks))
+            .is_err()
+        {
+            log::debug!("Bookmarks changed but nobody's listening");
+        }
+    }
+
+    fn window_views(&self) -> Vec<WindowView> {
+        self.windows
+            .iter()
+            .filter_map(|w| {
+                let focused = w.id == self.focused_window;
+                let buffer = if focused {
+                    &self.buffer
+                } else {
+                    self.buffer_by_number(w.buffer)?
+                };
+                Some(WindowView {
+                    id: w.id,
+                    text: buffer.text.view(),
+                    cursor_pos: if focused {
+                        buffer.cursor_pos.clone()
+                    } else {
+                        w.cursor_pos.clone()
+                    },
+                    top_line: w.top_line,
+                    left_col: w.left_col,
+                    selection: focused.then(|| self.selection()).flatten(),
+                    buffer_name: buffer.name(),
+                    modified: buffer.is_modified(),
+                    focused,
+                })
+            })
+            .collect()
+    }
+
+    fn tab_views(&self) -> Vec<TabView> {
+        self.tabs
+            .iter()
+            .enumerate()
+            .map(|(i, tab)| {
+                let current = i == self.current_tab;
+                let (windows, focused) = if current {
+                    (&self.windows, self.focused_window)
+                } else {
+                    (&tab.windows, tab.focused_window)
+                };
+                let name = windows
+                    .iter()
+                    .find(|w| w.id == focused)
+                    .and_then(|w| self.buffer_by_number(w.buffer))
+                    .map(|b| b.short_name())
+                    .unwrap_or_default();
+                let modified = windows
+                    .iter()
+                    .filter_map(|w| self.buffer_by_number(w.buffer))
+                    .any(|b| b.is_modified());
+                TabView {
+                    name,
+                    modified,
+                    current,
+                }
+            })
+            .collect()
+    }
+
+    fn buffer_by_number(&self, number: usize) -> Option<&Buffer> {
+        if self.buffer.number == number {
+            Some(&self.buffer)
+        } else {
+            self.hidden_buffers.iter().find(|b| b.number == number)
+        }
+    }
+
+    /// Records the screen size, which window sizes are worked out from.
+    pub fn resized(&mut self, size: (u16, u16)) {
+        self.screen_size = size;
+        self.notify_change();
+    }
+
+    pub fn highlight_updated(&mut self, highlight: HighlightState) {
+        self.buffer.highlight = Some(highlight);
+    }
+
     pub fn insert(&mut self, c: char) {
         match self.mode {
             Mode::Insert => {
-                let cur_ln = self.cursor_pos.line_number;
-                let cur_col = self.cursor_pos.colmun;
+                self.replace_pending_placeholder();
+                if self.options.autopairs && self.insert_paired(c) {
+                    self.notify_text_change();
+                    return;
+                }
 
-                let l = self.text.line_mut_populate(cur_ln);
+                let cur_ln = self.buffer.cursor_pos.line_number;
+                let cur_col = self.buffer.cursor_pos.colmun;
+
+                let l = self.buffer.text.line_mut_populate(cur_ln);
 
                 assert!(cur_col <= l.char_count());
 
                 let cur_ln = if c == '\n' {
-                    let rest_of_line = l.split_off(cur_col);
-                    self.text.insert_line_from_chars(cur_ln + 1, rest_of_line);
-                    self.cursor_pos.line_number += 1;
-                    self.cursor_pos.colmun = 0;
+                    // snippet fields are tracked within a line
+                    self.buffer.snippet_session = None;
+                    self.break_line(cur_ln, cur_col);
                     cur_ln + 1
+                } else if c == '\t' && self.options.expandtab {
+                    let line = l.content_string();
+                    let col = columns::display_col(&line, cur_col, self.options.tabstop);
+                    let sw = self.options.shiftwidth;
+                    for _ in 0..(sw - col % sw) {
+                        l.insert(self.buffer.cursor_pos.colmun, ' ');
+                        self.buffer.cursor_pos.colmun += 1;
+                    }
+                    cur_ln
                 } else {
                     l.insert(cur_col, c);
-                    self.cursor_pos.colmun += 1;
+                    self.buffer.cursor_pos.colmun += 1;
+                    if self.buffer.language.is_electric(c) {
+                        self.reindent_closing_line(cur_ln);
+                    }
                     cur_ln
                 };
 
@@ -228,128 +912,1823 @@ impl<'a> State {
         }
     }
 
-    fn commit_command(&'a mut self) -> EditorAction {
-        let action = self.command_line.clone();
-        self.shift_mode(Mode::Normal);
-        if action == "q" {
-            EditorAction::Quit
-        } else if action == "w" {
-            self.write();
-            EditorAction::None
+    /// Inserts pasted text at the cursor as a single edit, as it is: without
+    /// the indenting and pairing done for typed characters.
+    pub fn paste(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        match self.mode {
+            Mode::Command => {
+                self.command_line
+                    .extend(text.chars().take_while(|c| *c != '\n'));
+                self.notify_change();
+            }
+            Mode::Insert | Mode::Normal => {
+                self.selection_anchor = None;
+                self.replace_pending_placeholder();
+                // snippet fields are tracked within a line
+                if text.contains('\n') {
+                    self.buffer.snippet_session = None;
+                }
+                let (line_number, colmun) = self.buffer.text.insert_str(
+                    self.buffer.cursor_pos.line_number,
+                    self.buffer.cursor_pos.colmun,
+                    &text,
+                );
+                self.buffer.cursor_pos = CursorPos {
+                    line_number,
+                    colmun,
+                };
+                self.clamp_cursor();
+                self.notify_text_change();
+            }
+        }
+    }
+
+    /// Tab expands a snippet whose prefix is before the cursor, or moves on to
+    /// the next field of the snippet being filled in.
+    fn next_tab_stop(&mut self) {
+        if self.expand_snippet() {
+            self.notify_text_change();
+        } else if self.buffer.snippet_session.is_some() {
+            self.jump_tab_stop(1);
         } else {
-            EditorAction::None
+            self.insert('\t');
         }
     }
 
-    fn write(&mut self) {
-        if let Some(f) = self.file.as_mut() {
-            f.seek(SeekFrom::Start(0))
-                .expect("seeking to start of file");
-            let num_lines = self.text.line_count();
+    fn expand_snippet(&mut self) -> bool {
+        let ln = self.buffer.cursor_pos.line_number;
+        let col = self.buffer.cursor_pos.colmun;
+        let line = match self.buffer.text.line(ln) {
+            Some(l) => l.content_string(),
+            None => return false,
+        };
+        let before_cursor: String = line.chars().take(col).collect();
+        let snippet = match snippet::find_trigger(&self.buffer.snippets, &before_cursor) {
+            Some(s) => s.clone(),
+            None => return false,
+        };
 
-            let mut writer = BufWriter::new(f);
-            for (i, l) in self.text.iter_lines().enumerate() {
-                let write_result = writer.write_all(l.content_str().as_bytes()).and_then({
-                    |_| {
-                        if i < num_lines {
-                            writer.write(b"\n")
-                        } else {
-                            Ok(0)
-                        }
-                    }
-                });
+        let path = self.buffer.path.clone();
+        let current_line = line.to_string();
+        let expansion = snippet::expand(snippet.body(), &|name: &str| {
+            snippet_variable(name, path.as_deref(), ln, &current_line)
+        });
+        let indent = language::leading_whitespace(&line).to_string();
+        let (lines, field_positions) =
+            snippet::layout(&expansion, &indent, &self.options.indent_unit());
 
-                if let Err(e) = write_result {
-                    self.status_text.clear();
-                    self.status_text
-                        .push_str(&format!("Failed to save file: {}", e));
-                    return;
+        let start_col = col - snippet.prefix().chars().count();
+        let l = self.buffer.text.line_mut_populate(ln);
+        for _ in start_col..col {
+            l.remove_char(start_col);
+        }
+        let rest_of_line = l.split_off(start_col);
+        let mut line_ids = Vec::with_capacity(lines.len());
+        for (i, content) in lines.iter().enumerate() {
+            let mut chars: Vec<char> = content.chars().collect();
+            if i == lines.len() - 1 {
+                chars.extend_from_slice(&rest_of_line);
+            }
+            if i == 0 {
+                let l = self.buffer.text.line_mut_populate(ln);
+                for c in chars {
+                    l.insert(l.char_count(), c);
                 }
+                line_ids.push(l.id());
+            } else {
+                self.buffer.text.insert_line_from_chars(ln + i, chars);
+                line_ids.push(
+                    self.buffer
+                        .text
+                        .line(ln + i)
+                        .expect("line just inserted")
+                        .id(),
+                );
             }
-
-            let f = writer.get_mut();
-            let new_file_length = f
-                .seek(SeekFrom::Current(0))
-                .expect("Unable to determine length of file being written");
-            f.set_len(new_file_length)
-                .expect("Unable to truncate file after writing");
         }
-    }
 
-    fn delete(&mut self) {
-        match self.mode {
-            Mode::Insert => {
-                let cur_col = self.cursor_pos.colmun;
-                if cur_col > 0 {
-                    let line = self.text.line_mut(self.cursor_pos.line_number);
-                    if let Some(line) = line {
-                        line.remove_char(cur_col - 1);
-                        self.cursor_pos.colmun = self.cursor_pos.colmun.saturating_sub(1);
-                    }
-                } else {
-                    let cur_row = self.cursor_pos.line_number;
+        let fields: Vec<SnippetField> = expansion
+            .fields
+            .iter()
+            .zip(field_positions)
+            .map(|(f, (line, col, len))| SnippetField {
+                number: f.number,
+                line: line_ids[line],
+                col: if line == 0 { col + start_col } else { col },
+                len,
+                choices: f.choices.clone(),
+            })
+            .collect();
+        let mut order: Vec<u32> = fields
+            .iter()
+            .map(|f| f.number)
+            .filter(|n| *n != 0)
+            .collect();
+        order.sort_unstable();
+        order.dedup();
+        order.push(0);
 
-                    if cur_row == 0 {
-                        if self.text.line_count() == 1
-                            && self.text.line(0).expect("0th line missing").char_count() == 0
-                        {
-                            self.text.remove_line(0);
-                        }
-                        return;
-                    }
+        self.buffer.snippet_session = Some(SnippetSession {
+            fields,
+            order,
+            current: 0,
+            placeholder_pending: false,
+            line_len: 0,
+        });
+        self.enter_snippet_field();
+        true
+    }
 
-                    let end_of_prev_line = self
-                        .text
-                        .line(cur_row - 1)
-                        .map(|l| l.char_count())
-                        .unwrap_or(0);
+    fn jump_tab_stop(&mut self, direction: isize) {
+        let current = match self.buffer.snippet_session.as_ref() {
+            Some(session) => session.current,
+            None => return,
+        };
+        self.commit_snippet_field();
 
-                    {
-                        let cur_line = self.text.remove_line(cur_row);
-                        if let Some(cur_line) = cur_line {
-                            let prev_row = self.text.line_mut(cur_row - 1);
-                            if let Some(prev_row) = prev_row {
+        if let Some(session) = self.buffer.snippet_session.as_mut() {
+            session.current = if direction.is_negative() {
+                current.saturating_sub(1)
+            } else {
+                (current + 1).min(session.order.len() - 1)
+            };
+        }
+        self.enter_snippet_field();
+        self.notify_text_change();
+    }
+
+    /// Finds the line with `id`, searching outwards from the cursor, which is
+    /// usually on or near the lines being looked for.
+    fn line_number_of(&self, id: LineId) -> Option<usize> {
+        let line_count = self.buffer.text.line_count();
+        let start = self
+            .buffer
+            .cursor_pos
+            .line_number
+            .min(line_count.saturating_sub(1));
+        let is_line = |ln: usize| self.buffer.text.line(ln).map(|l| l.id()) == Some(id);
+        for distance in 0..line_count {
+            let below = start + distance;
+            if below < line_count && is_line(below) {
+                return Some(below);
+            }
+            match start.checked_sub(distance) {
+                Some(above) if distance > 0 && is_line(above) => return Some(above),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Moves the cursor to the current field, finishing the snippet when that
+    /// is the final tab stop.
+    fn enter_snippet_field(&mut self) {
+        let session = match self.buffer.snippet_session.as_mut() {
+            Some(s) => s,
+            None => return,
+        };
+        let number = session.order[session.current];
+        let (line, col, len, choices) = match session.fields.iter().find(|f| f.number == number) {
+            Some(f) => (f.line, f.col, f.len, f.choices.clone()),
+            None => return,
+        };
+        session.placeholder_pending = len > 0;
+        if number == 0 {
+            self.buffer.snippet_session = None;
+        }
+
+        if let Some(ln) = self.line_number_of(line) {
+            self.buffer.cursor_pos.line_number = ln;
+            self.buffer.cursor_pos.colmun = col;
+        }
+        self.record_field_line_len();
+        if !choices.is_empty() {
+            self.status_text = format!("choices: {}", choices.join(" | "));
+        }
+    }
+
+    /// Records what was typed into the current field, and copies it into any
+    /// fields that mirror it.
+    fn commit_snippet_field(&mut self) {
+        let (number, primary) = match self.buffer.snippet_session.as_ref() {
+            Some(s) => {
+                let number = s.order[s.current];
+                match s.fields.iter().position(|f| f.number == number) {
+                    Some(p) => (number, p),
+                    None => return,
+                }
+            }
+            None => return,
+        };
+
+        let (line, col, len) = {
+            let session = self.buffer.snippet_session.as_ref().unwrap();
+            let f = &session.fields[primary];
+            // whatever the line has grown by went into the field, including
+            // closers typed by autopairing
+            let typed_len = match self.line_number_of(f.line) {
+                Some(ln)
+                    if !session.placeholder_pending
+                        && ln == self.buffer.cursor_pos.line_number
+                        && self.buffer.cursor_pos.colmun >= f.col =>
+                {
+                    (f.len + self.line_len(ln)).saturating_sub(session.line_len)
+                }
+                _ => f.len,
+            };
+            (f.line, f.col, typed_len)
+        };
+        self.resize_snippet_field(primary, len);
+
+        let ln = match self.line_number_of(line) {
+            Some(ln) => ln,
+            None => return,
+        };
+        let content: Vec<char> = self
+            .buffer
+            .text
+            .line(ln)
+            .map(|l| l.content_string().chars().skip(col).take(len).collect())
+            .unwrap_or_default();
+
+        let mirrors: Vec<usize> = self
+            .buffer
+            .snippet_session
+            .as_ref()
+            .unwrap()
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(i, f)| f.number == number && *i != primary)
+            .map(|(i, _)| i)
+            .collect();
+        for m in mirrors {
+            self.replace_snippet_field(m, &content);
+        }
+    }
+
+    fn replace_snippet_field(&mut self, index: usize, content: &[char]) {
+        let (line, col, len) = match self.buffer.snippet_session.as_ref() {
+            Some(s) => (
+                s.fields[index].line,
+                s.fields[index].col,
+                s.fields[index].len,
+            ),
+            None => return,
+        };
+        let ln = match self.line_number_of(line) {
+            Some(ln) => ln,
+            None => return,
+        };
+        let l = self.buffer.text.line_mut_populate(ln);
+        for _ in 0..len {
+            l.remove_char(col);
+        }
+        for (i, c) in content.iter().enumerate() {
+            l.insert(col + i, *c);
+        }
+        self.resize_snippet_field(index, content.len());
+    }
+
+    /// Sets a field's length, moving the fields after it on the same line.
+    fn resize_snippet_field(&mut self, index: usize, new_len: usize) {
+        if let Some(session) = self.buffer.snippet_session.as_mut() {
+            let (line, col, old_len) = {
+                let f = &session.fields[index];
+                (f.line, f.col, f.len)
+            };
+            for f in session.fields.iter_mut() {
+                if f.line == line && f.col > col {
+                    f.col = (f.col + new_len).saturating_sub(old_len);
+                }
+            }
+            session.fields[index].len = new_len;
+        }
+    }
+
+    fn line_len(&self, ln: usize) -> usize {
+        self.buffer
+            .text
+            .line(ln)
+            .map(|l| l.char_count())
+            .unwrap_or(0)
+    }
+
+    /// Notes how long the current field's line is now, to measure the field
+    /// by when it's committed.
+    fn record_field_line_len(&mut self) {
+        let line = match self.buffer.snippet_session.as_ref() {
+            Some(s) => {
+                let number = s.order[s.current];
+                match s.fields.iter().find(|f| f.number == number) {
+                    Some(f) => f.line,
+                    None => return,
+                }
+            }
+            None => return,
+        };
+        let len = self.line_number_of(line).map(|ln| self.line_len(ln));
+        if let (Some(s), Some(len)) = (self.buffer.snippet_session.as_mut(), len) {
+            s.line_len = len;
+        }
+    }
+
+    /// Typing over a placeholder removes it first. Returns whether there was
+    /// one to remove.
+    fn replace_pending_placeholder(&mut self) -> bool {
+        let primary = match self.buffer.snippet_session.as_ref() {
+            Some(s) if s.placeholder_pending => {
+                let number = s.order[s.current];
+                s.fields.iter().position(|f| f.number == number)
+            }
+            _ => None,
+        };
+        if let Some(primary) = primary {
+            self.replace_snippet_field(primary, &[]);
+            if let Some(s) = self.buffer.snippet_session.as_mut() {
+                s.placeholder_pending = false;
+            }
+            self.record_field_line_len();
+        }
+        primary.is_some()
+    }
+
+    fn cycle_choice(&mut self) {
+        let (primary, next) = match self.buffer.snippet_session.as_ref() {
+            Some(s) => {
+                let number = s.order[s.current];
+                let primary = match s.fields.iter().position(|f| f.number == number) {
+                    Some(p) => p,
+                    None => return,
+                };
+                let f = &s.fields[primary];
+                if f.choices.is_empty() {
+                    return;
+                }
+                let current: String = self
+                    .line_number_of(f.line)
+                    .and_then(|ln| self.buffer.text.line(ln))
+                    .map(|l| l.content_string().chars().skip(f.col).take(f.len).collect())
+                    .unwrap_or_default();
+                let idx = f.choices.iter().position(|c| *c == current);
+                let next = idx.map(|i| (i + 1) % f.choices.len()).unwrap_or(0);
+                (primary, f.choices[next].clone())
+            }
+            None => return,
+        };
+
+        let content: Vec<char> = next.chars().collect();
+        self.replace_snippet_field(primary, &content);
+        if let Some(s) = self.buffer.snippet_session.as_mut() {
+            s.placeholder_pending = true;
+            self.buffer.cursor_pos.colmun = s.fields[primary].col;
+        }
+        self.record_field_line_len();
+        self.notify_text_change();
+    }
+
+    /// Handles typing one half of a bracket or quote pair: either skipping over
+    /// a closing character that's already there, or inserting both halves.
+    /// Returns false if `c` should be inserted normally.
+    fn insert_paired(&mut self, c: char) -> bool {
+        let ln = self.buffer.cursor_pos.line_number;
+        let col = self.buffer.cursor_pos.colmun;
+        let (line_id, line_rev, line) = match self.buffer.text.line(ln) {
+            Some(l) => (l.id(), l.rev(), l.content_string()),
+            None => return false,
+        };
+        let prev = line.chars().nth(col.wrapping_sub(1));
+        let next = line.chars().nth(col);
+
+        if next == Some(c) && self.buffer.language.is_closing_pair(c) {
+            self.buffer.cursor_pos.colmun += 1;
+            if self.buffer.language.is_electric(c) {
+                self.reindent_closing_line(ln);
+            }
+            return true;
+        }
+
+        let close = match self.buffer.language.closing_pair(c) {
+            Some(close) => close,
+            None => return false,
+        };
+
+        // the highlighting lags behind typing, so it's only used if it's of
+        // the line as it is now
+        let in_string_or_comment = match self
+            .buffer
+            .highlight
+            .as_ref()
+            .and_then(|hl| hl.line_by_id(line_id))
+        {
+            Some(hl) if hl.is_current(line_rev) => hl.in_string_or_comment(col),
+            _ => self.buffer.language.in_string_or_comment(&line, col),
+        };
+        if in_string_or_comment {
+            return false;
+        }
+
+        let next_allows_pair = match next {
+            None => true,
+            Some(n) => n.is_whitespace() || (self.buffer.language.is_closing_pair(n) && n != c),
+        };
+        let prev_allows_pair = c != close
+            || !prev
+                .map(|p| p.is_alphanumeric() || p == c || p == '\\')
+                .unwrap_or(false);
+        if !next_allows_pair || !prev_allows_pair {
+            return false;
+        }
+
+        let l = self.buffer.text.line_mut_populate(ln);
+        l.insert(col, c);
+        l.insert(col + 1, close);
+        self.buffer.cursor_pos.colmun += 1;
+        true
+    }
+
+    fn break_line(&mut self, ln: usize, col: usize) {
+        let l = self.buffer.text.line_mut_populate(ln);
+        let before_cursor: String = l.content_string()[..].chars().take(col).collect();
+        let rest_of_line = l.split_off(col);
+
+        let indent = self
+            .buffer
+            .language
+            .newline_indent(&before_cursor, &self.options.indent_unit());
+        let rest_start = rest_of_line
+            .iter()
+            .position(|c| !c.is_whitespace())
+            .unwrap_or(rest_of_line.len());
+        let rest_of_line = &rest_of_line[rest_start..];
+
+        let closes_opened_block = match (
+            before_cursor.trim_end().chars().last(),
+            rest_of_line.first(),
+        ) {
+            (Some(open), Some(close)) => language::matching_open(*close) == Some(open),
+            _ => false,
+        };
+
+        let mut new_line: Vec<char> = indent.chars().collect();
+        if closes_opened_block {
+            let mut closing_line: Vec<char> = language::leading_whitespace(&before_cursor)
+                .chars()
+                .collect();
+            closing_line.extend_from_slice(rest_of_line);
+            self.buffer
+                .text
+                .insert_line_from_chars(ln + 1, closing_line);
+        } else {
+            new_line.extend_from_slice(rest_of_line);
+        }
+        self.buffer.text.insert_line_from_chars(ln + 1, new_line);
+
+        self.buffer.cursor_pos.line_number = ln + 1;
+        self.buffer.cursor_pos.colmun = indent.chars().count();
+    }
+
+    /// Re-indents a line that has just had a closing bracket typed as its first
+    /// non-whitespace character, lining it up with the line holding the
+    /// matching opening bracket.
+    fn reindent_closing_line(&mut self, ln: usize) {
+        let line = match self.buffer.text.line(ln) {
+            Some(l) => l.content_string(),
+            None => return,
+        };
+        let current_indent = language::leading_whitespace(&line);
+        let closer = match line[current_indent.len()..].chars().next() {
+            Some(c) => c,
+            None => return,
+        };
+        if current_indent.chars().count() + 1 != self.buffer.cursor_pos.colmun {
+            return;
+        }
+
+        let new_indent = match self.indent_of_matching_open(ln, closer) {
+            Some(indent) => indent,
+            None => language::dedent(current_indent, &self.options.indent_unit()).to_string(),
+        };
+        if new_indent == current_indent {
+            return;
+        }
+
+        let current_indent_chars = current_indent.chars().count();
+        let l = self.buffer.text.line_mut_populate(ln);
+        for _ in 0..current_indent_chars {
+            l.remove_char(0);
+        }
+        for (i, c) in new_indent.chars().enumerate() {
+            l.insert(i, c);
+        }
+        self.buffer.cursor_pos.colmun = new_indent.chars().count() + 1;
+    }
+
+    fn indent_of_matching_open(&self, closing_ln: usize, closer: char) -> Option<String> {
+        let opener = language::matching_open(closer)?;
+        let mut depth = 0usize;
+        for ln in (0..closing_ln).rev() {
+            let line = self.buffer.text.line(ln)?.content_string();
+            for c in line.chars().rev() {
+                if c == closer {
+                    depth += 1;
+                } else if c == opener {
+                    if depth == 0 {
+                        return Some(language::leading_whitespace(&line).to_string());
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        None
+    }
+
+    /// Changes the indentation of lines `start..=end` by `levels` shift widths.
+    fn shift_lines(&mut self, start: usize, end: usize, levels: isize) {
+        let sw = self.options.shiftwidth;
+        let tabstop = self.options.tabstop;
+
+        for ln in start..=end.min(self.buffer.text.line_count().saturating_sub(1)) {
+            let line = match self.buffer.text.line(ln) {
+                Some(l) => l.content_string(),
+                None => continue,
+            };
+            let old_indent = language::leading_whitespace(&line);
+            if old_indent.len() == line.len() {
+                continue;
+            }
+
+            let width = columns::display_col(old_indent, old_indent.chars().count(), tabstop);
+            let new_width = if levels.is_negative() {
+                let levels = levels.unsigned_abs();
+                let to_previous_stop = match width % sw {
+                    0 => sw,
+                    partial => partial,
+                };
+                width.saturating_sub(to_previous_stop + sw * (levels - 1))
+            } else {
+                (width / sw + levels as usize) * sw
+            };
+            let new_indent = self.options.indent_string(new_width);
+
+            let old_len = old_indent.chars().count();
+            let new_len = new_indent.chars().count();
+            let l = self.buffer.text.line_mut_populate(ln);
+            for _ in 0..old_len {
+                l.remove_char(0);
+            }
+            for (i, c) in new_indent.chars().enumerate() {
+                l.insert(i, c);
+            }
+
+            if ln == self.buffer.cursor_pos.line_number {
+                self.buffer.cursor_pos.colmun = if self.buffer.cursor_pos.colmun < old_len {
+                    new_len
+                } else {
+                    self.buffer.cursor_pos.colmun + new_len - old_len
+                };
+            }
+        }
+
+        self.notify_text_change();
+    }
+
+    /// Applies `op` to lines `start..=end`.
+    fn line_op(&mut self, start: usize, end: usize, op: LineOp) {
+        let line_count = self.buffer.text.line_count();
+        if line_count == 0 || start >= line_count {
+            return;
+        }
+        let end = end.min(line_count - 1);
+        let lines = start..end + 1;
+        // the cursor only moves along with the lines if it is on one of them
+        let cursor_in_range = lines.contains(&self.buffer.cursor_pos.line_number);
+
+        match op {
+            LineOp::Delete => {
+                for _ in lines {
+                    self.buffer.text.remove_line(start);
+                }
+                self.buffer.cursor_pos.line_number =
+                    start.min(self.buffer.text.line_count().saturating_sub(1));
+            }
+            LineOp::Duplicate => {
+                self.buffer.text.duplicate_lines(lines);
+                if cursor_in_range {
+                    self.buffer.cursor_pos.line_number += end + 1 - start;
+                }
+            }
+            LineOp::MoveUp => {
+                if start > 0 {
+                    for ln in lines {
+                        self.buffer.text.swap_lines(ln - 1, ln);
+                    }
+                    if cursor_in_range {
+                        self.buffer.cursor_pos.line_number -= 1;
+                    }
+                }
+            }
+            LineOp::MoveDown => {
+                if end + 1 < line_count {
+                    for ln in lines.rev() {
+                        self.buffer.text.swap_lines(ln, ln + 1);
+                    }
+                    if cursor_in_range {
+                        self.buffer.cursor_pos.line_number += 1;
+                    }
+                }
+            }
+            LineOp::Join => {
+                for _ in 0..(end - start).max(1) {
+                    if let Some(col) = self.buffer.text.join_lines(start) {
+                        self.buffer.cursor_pos.line_number = start;
+                        self.buffer.cursor_pos.colmun = col;
+                    }
+                }
+            }
+            LineOp::ChangeCase(case) => self.buffer.text.change_case(lines, case),
+            LineOp::Sort => self.buffer.text.sort_lines(lines),
+            LineOp::Reverse => self.buffer.text.reverse_lines(lines),
+            LineOp::Unique => {
+                let removed = self.buffer.text.unique_lines(lines);
+                self.status_text = format!("{} duplicate lines removed", removed);
+            }
+            LineOp::ToggleComment => self.toggle_comment(lines),
+        }
+
+        self.clamp_cursor();
+        self.notify_text_change();
+    }
+
+    fn toggle_comment(&mut self, lines: Range<usize>) {
+        let markers = match self.buffer.language.comment_markers() {
+            Some(markers) => markers,
+            None => {
+                self.status_text =
+                    format!("No comment markers for {}", self.buffer.language.name());
+                return;
+            }
+        };
+
+        let before: Vec<String> = lines
+            .clone()
+            .filter_map(|ln| self.buffer.text.line(ln))
+            .map(|l| l.content_string().to_string())
+            .collect();
+        let after = comment::toggle(&before, &markers, self.options.tabstop);
+
+        for (ln, (old, new)) in lines.zip(before.iter().zip(after)) {
+            if *old == new {
+                continue;
+            }
+            if ln == self.buffer.cursor_pos.line_number {
+                let (old_len, new_len) = (old.chars().count(), new.chars().count());
+                self.buffer.cursor_pos.colmun =
+                    (self.buffer.cursor_pos.colmun + new_len).saturating_sub(old_len);
+            }
+            if let Some(l) = self.buffer.text.line_mut(ln) {
+                l.set_content(new.chars().collect());
+            }
+        }
+    }
+
+    /// Replaces lines `start..=end` with the output of `command` run with
+    /// them as its input. The text is left alone if the command fails.
+    fn filter_lines(&mut self, start: usize, end: usize, command: &str) -> Result<(), String> {
+        let mut input = String::new();
+        for line in self.buffer.text.iter_line_range(start, end + 1) {
+            input.push_str(&line.content_str());
+            input.push('\n');
+        }
+
+        let output = shell::run(command, Some(input))?;
+
+        let line_count = self.buffer.text.line_count();
+        let mut lines: Vec<String> = Vec::with_capacity(line_count);
+        let lines_from = |from| {
+            self.buffer
+                .text
+                .iter_line_range(from, line_count)
+                .map(|l| l.content_str().to_string())
+        };
+        lines.extend(lines_from(0).take(start));
+        lines.extend(output.lines().map(|l| l.to_string()));
+        lines.extend(lines_from(end + 1));
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+        self.buffer.text.apply_lines(&lines);
+
+        self.status_text = format!("{} lines filtered through {}", end + 1 - start, command);
+        self.buffer.cursor_pos.line_number = start;
+        self.buffer.cursor_pos.colmun = 0;
+        self.clamp_cursor();
+        self.notify_text_change();
+        Ok(())
+    }
+
+    fn clamp_cursor(&mut self) {
+        let last_line = self.buffer.text.line_count().saturating_sub(1);
+        self.buffer.cursor_pos.line_number = self.buffer.cursor_pos.line_number.min(last_line);
+        let line_len = self
+            .buffer
+            .text
+            .line(self.buffer.cursor_pos.line_number)
+            .map(|l| l.char_count())
+            .unwrap_or(0);
+        self.buffer.cursor_pos.colmun = self.buffer.cursor_pos.colmun.min(line_len);
+    }
+
+    fn commit_command(&'a mut self) -> EditorAction {
+        let action = self.command_line.clone();
+        self.shift_mode(Mode::Normal);
+
+        match ExCommand::parse(&action).and_then(|c| self.run_ex_command(&c)) {
+            Ok(action) => action,
+            Err(e) => {
+                self.status_text = e;
+                self.notify_change();
+                EditorAction::None
+            }
+        }
+    }
+
+    fn run_ex_command(&mut self, c: &ExCommand) -> Result<EditorAction, String> {
+        let current_line = self.buffer.cursor_pos.line_number;
+        let line_count = self.buffer.text.line_count();
+
+        if c.is("q", "quit") {
+            if self.windows.len() > 1 {
+                self.close_window()?;
+            } else if self.tabs.len() > 1 {
+                self.close_tab()?;
+            } else {
+                if !c.bang() {
+                    if let Some(b) = self.modified_buffer() {
+                        return Err(format!(
+                            "No write since last change (buffer {}) (add ! to override)",
+                            b.number
+                        ));
+                    }
+                }
+                return Ok(EditorAction::Quit);
+            }
+        } else if c.is("tabnew", "tabnew") || c.is("tabe", "tabedit") {
+            self.new_tab(c.args())?;
+        } else if c.is("tabc", "tabclose") {
+            self.close_tab()?;
+        } else if c.is("tabn", "tabnext") {
+            self.cycle_tab(true);
+        } else if c.is("tabp", "tabprevious") {
+            self.cycle_tab(false);
+        } else if c.is("sp", "split") || c.is("vs", "vsplit") {
+            let orientation = if c.name().starts_with('v') {
+                Orientation::SideBySide
+            } else {
+                Orientation::Stacked
+            };
+            self.split_window(orientation);
+            if !c.args().is_empty() {
+                self.edit(c.args())?;
+            }
+        } else if c.is("clo", "close") {
+            self.close_window()?;
+        } else if c.is("res", "resize") {
+            self.resize_window(Orientation::Stacked, c.args())?;
+        } else if c.is("vert", "vertical") {
+            let modified = ExCommand::parse(c.args())?;
+            if modified.is("res", "resize") {
+                self.resize_window(Orientation::SideBySide, modified.args())?;
+            } else if modified.is("sp", "split") {
+                self.split_window(Orientation::SideBySide);
+                if !modified.args().is_empty() {
+                    self.edit(modified.args())?;
+                }
+            } else {
+                return Err(format!("Can't use :vertical with {}", modified.name()));
+            }
+        } else if c.is("w", "write") {
+            // formatting can abort the save, so it goes before any cleanups
+            if self.options.formatonsave {
+                self.format().map_err(|e| format!("Not saved: {}", e))?;
+            }
+            self.clean_up_for_save();
+            self.write();
+        } else if c.is("se", "set") {
+            for arg in c.args().split_whitespace() {
+                self.options.set(arg)?;
+            }
+            self.notify_change();
+        } else if c.is("colo", "colorscheme") {
+            self.set_colorscheme(c.args())?;
+        } else if c.is("e", "edit") {
+            self.edit(c.args())?;
+        } else if c.is("bn", "bnext") {
+            self.cycle_buffer(true);
+        } else if c.is("bp", "bprevious") {
+            self.cycle_buffer(false);
+        } else if c.is("bd", "bdelete") {
+            self.delete_buffer(c.bang())?;
+        } else if c.is("b", "buffer") {
+            let number = c
+                .args()
+                .parse()
+                .map_err(|_| format!("Invalid buffer number: {}", c.args()))?;
+            self.switch_buffer(number)?;
+        } else if c.is("ls", "ls") || c.is("buffers", "buffers") {
+            self.status_text = self.list_buffers();
+            self.notify_change();
+        } else if c.name() == "!" {
+            match c.range() {
+                Some(_) => {
+                    let (start, end) = c.lines(current_line, line_count)?;
+                    self.filter_lines(start, end, c.args())?;
+                }
+                None => {
+                    let output = shell::run(c.args(), None)?;
+                    self.status_text = output.lines().collect::<Vec<_>>().join(" | ");
+                    self.notify_change();
+                }
+            }
+        } else if c.name().is_empty() {
+            let (_, end) = c.lines(current_line, line_count)?;
+            self.buffer.cursor_pos.line_number = end;
+            self.clamp_cursor();
+            self.notify_change();
+        } else if c.is("sor", "sort") {
+            let (start, end) = c.lines(current_line, line_count)?;
+            self.line_op(start, end, LineOp::Sort);
+            if c.bang() {
+                self.line_op(start, end, LineOp::Reverse);
+            }
+        } else if let Some(op) = ex_line_op(c) {
+            let (start, end) = c.lines(current_line, line_count)?;
+            self.line_op(start, end, op);
+        } else {
+            return Err(format!("Not an editor command: {}", c.name()));
+        }
+
+        Ok(EditorAction::None)
+    }
+
+    /// Changes the colour scheme to the one called `name`, or shows the
+    /// current one's name if `name` is empty.
+    fn set_colorscheme(&mut self, name: &str) -> Result<(), String> {
+        if name.is_empty() {
+            self.status_text = self.colorscheme.clone();
+            self.notify_change();
+            return Ok(());
+        }
+
+        let theme = theme::load(name)?;
+        self.colorscheme = name.to_string();
+        if self.pubsub.send(theme::topic(), Arc::new(theme)).is_err() {
+            log::debug!("Colour scheme changed but nobody's listening");
+        }
+        self.notify_text_change();
+        Ok(())
+    }
+
+    /// Switches to the buffer for `path`, opening it if needed.
+    fn edit(&mut self, path: &str) -> Result<(), String> {
+        if path.is_empty() {
+            return Err("No file name".to_string());
+        }
+
+        if self.buffer.is_editing(Path::new(path)) {
+            return Ok(());
+        }
+        if let Some(number) = self
+            .hidden_buffers
+            .iter()
+            .find(|b| b.is_editing(Path::new(path)))
+            .map(|b| b.number)
+        {
+            return self.switch_buffer(number);
+        }
+
+        let (buffer, status_text) = Buffer::open(OsStr::new(path), self.next_buffer_number)
+            .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        self.next_buffer_number += 1;
+        self.status_text = status_text;
+        self.show_buffer(buffer);
+        Ok(())
+    }
+
+    fn switch_buffer(&mut self, number: usize) -> Result<(), String> {
+        if number == self.buffer.number {
+            return Ok(());
+        }
+        let index = self
+            .hidden_buffers
+            .iter()
+            .position(|b| b.number == number)
+            .ok_or_else(|| format!("No buffer {}", number))?;
+        let buffer = self.hidden_buffers.remove(index);
+        self.show_buffer(buffer);
+        Ok(())
+    }
+
+    fn cycle_buffer(&mut self, forward: bool) {
+        let current = self.buffer.number;
+        let numbers = self.hidden_buffers.iter().map(|b| b.number);
+        let target = if forward {
+            numbers
+                .clone()
+                .find(|n| *n > current)
+                .or_else(|| numbers.clone().next())
+        } else {
+            numbers
+                .clone()
+                .rev()
+                .find(|n| *n < current)
+                .or_else(|| numbers.clone().next_back())
+        };
+        if let Some(number) = target {
+            let _ = self.switch_buffer(number);
+        }
+    }
+
+    /// Makes `buffer` the current one, keeping the previous one open.
+    fn show_buffer(&mut self, buffer: Buffer) {
+        let previous = std::mem::replace(&mut self.buffer, buffer);
+        self.hidden_buffers.push(previous);
+        self.hidden_buffers.sort_by_key(|b| b.number);
+        let (focused, number) = (self.focused_window, self.buffer.number);
+        if let Some(w) = self.windows.iter_mut().find(|w| w.id == focused) {
+            w.buffer = number;
+        }
+        self.notify_text_change();
+    }
+
+    fn window_command(&mut self, command: WindowCommand) -> Result<(), String> {
+        match command {
+            WindowCommand::Split(orientation) => self.split_window(orientation),
+            WindowCommand::Close => self.close_window()?,
+            WindowCommand::Focus(side) => {
+                let area = self.text_area();
+                if let Some(id) = self.layout.neighbour(self.focused_window, side, area) {
+                    self.focus_window(id);
+                }
+            }
+            WindowCommand::FocusNext => {
+                let ids = self.layout.windows();
+                let current = ids.iter().position(|id| *id == self.focused_window);
+                let next = current.map(|i| (i + 1) % ids.len()).unwrap_or(0);
+                self.focus_window(ids[next]);
+            }
+            WindowCommand::Resize(orientation, delta) => {
+                let area = self.text_area();
+                if !self
+                    .layout
+                    .resize(self.focused_window, orientation, delta, area)
+                {
+                    return Err("No split to resize".to_string());
+                }
+                self.notify_change();
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the focused window, putting a new window onto the same buffer
+    /// above or to the left and focusing it.
+    fn split_window(&mut self, orientation: Orientation) {
+        let id = self.new_window_id();
+        self.layout.split(self.focused_window, id, orientation);
+        let top_line = self.top_line();
+        self.windows.push(Window {
+            id,
+            buffer: self.buffer.number,
+            cursor_pos: self.buffer.cursor_pos.clone(),
+            top_line,
+            left_col: self.left_col(),
+        });
+        self.focus_window(id);
+    }
+
+    /// Closes the focused window, focusing the one that takes over its space.
+    fn close_window(&mut self) -> Result<(), String> {
+        if self.windows.len() <= 1 {
+            return Err("Can't close the last window".to_string());
+        }
+
+        let area = self.text_area();
+        let closed = self.focused_window;
+        let closed_rect = self.layout.arrange(area).rect_of(closed);
+        self.layout.close(closed);
+        self.windows.retain(|w| w.id != closed);
+
+        let arrangement = self.layout.arrange(area);
+        let next = closed_rect
+            .and_then(|c| {
+                arrangement
+                    .windows
+                    .iter()
+                    .find(|(_, r)| r.contains(c.x, c.y))
+            })
+            .unwrap_or(&arrangement.windows[0])
+            .0;
+        self.enter_window(next);
+        Ok(())
+    }
+
+    /// `:resize` with `+N` or `-N` to grow or shrink the focused window, or
+    /// `N` to set its size.
+    fn resize_window(&mut self, orientation: Orientation, arg: &str) -> Result<(), String> {
+        let amount: i32 = arg.parse().map_err(|_| format!("Invalid size: {}", arg))?;
+        let delta = if arg.starts_with(['+', '-']) {
+            amount
+        } else {
+            let area = self.text_area();
+            let rect = self
+                .layout
+                .arrange(area)
+                .rect_of(self.focused_window)
+                .ok_or("No focused window")?;
+            amount
+                - match orientation {
+                    Orientation::Stacked => rect.height,
+                    Orientation::SideBySide => rect.width,
+                } as i32
+        };
+        self.window_command(WindowCommand::Resize(orientation, delta))
+    }
+
+    fn new_window_id(&mut self) -> WindowId {
+        let id = self.next_window_id;
+        self.next_window_id = id.next();
+        id
+    }
+
+    fn text_area(&self) -> layout::Rect {
+        layout::text_area(self.screen_size, self.tabs.len() > 1)
+    }
+
+    /// Opens a new tab page after the current one, showing `path` or a new
+    /// empty buffer.
+    fn new_tab(&mut self, path: &str) -> Result<(), String> {
+        self.store_tab();
+        let window = Window {
+            id: self.new_window_id(),
+            buffer: self.buffer.number,
+            cursor_pos: self.buffer.cursor_pos.clone(),
+            top_line: 0,
+            left_col: 0,
+        };
+        let tab = TabPage {
+            layout: Layout::Window(window.id),
+            windows: vec![window.clone()],
+            focused_window: window.id,
+        };
+        self.tabs.insert(self.current_tab + 1, tab);
+        self.load_tab(self.current_tab + 1);
+
+        if path.is_empty() {
+            let buffer = Buffer::empty(self.next_buffer_number);
+            self.next_buffer_number += 1;
+            self.show_buffer(buffer);
+            Ok(())
+        } else {
+            self.edit(path)
+        }
+    }
+
+    fn close_tab(&mut self) -> Result<(), String> {
+        if self.tabs.len() <= 1 {
+            return Err("Can't close the last tab page".to_string());
+        }
+        self.tabs.remove(self.current_tab);
+        self.load_tab(self.current_tab.min(self.tabs.len() - 1));
+        Ok(())
+    }
+
+    fn cycle_tab(&mut self, forward: bool) {
+        let count = self.tabs.len();
+        if count > 1 {
+            self.store_tab();
+            let next = if forward {
+                (self.current_tab + 1) % count
+            } else {
+                (self.current_tab + count - 1) % count
+            };
+            self.load_tab(next);
+        }
+    }
+
+    /// Saves the current tab page's windows into its entry in `tabs`.
+    fn store_tab(&mut self) {
+        let (focused, cursor) = (self.focused_window, self.buffer.cursor_pos.clone());
+        if let Some(w) = self.windows.iter_mut().find(|w| w.id == focused) {
+            w.cursor_pos = cursor;
+        }
+        self.tabs[self.current_tab] = TabPage {
+            layout: self.layout.clone(),
+            windows: self.windows.clone(),
+            focused_window: self.focused_window,
+        };
+    }
+
+    fn load_tab(&mut self, index: usize) {
+        let tab = self.tabs[index].clone();
+        self.current_tab = index;
+        self.layout = tab.layout;
+        self.windows = tab.windows;
+        self.enter_window(tab.focused_window);
+    }
+
+    fn focus_window(&mut self, id: WindowId) {
+        if id == self.focused_window {
+            return;
+        }
+        let (focused, cursor) = (self.focused_window, self.buffer.cursor_pos.clone());
+        if let Some(w) = self.windows.iter_mut().find(|w| w.id == focused) {
+            w.cursor_pos = cursor;
+        }
+        self.enter_window(id);
+    }
+
+    /// Makes `id` the focused window, showing its buffer and cursor.
+    fn enter_window(&mut self, id: WindowId) {
+        let (buffer, cursor) = match self.windows.iter().find(|w| w.id == id) {
+            Some(w) => (w.buffer, w.cursor_pos.clone()),
+            None => return,
+        };
+        self.focused_window = id;
+        self.selection_anchor = None;
+        if buffer != self.buffer.number {
+            let _ = self.switch_buffer(buffer);
+        }
+        self.buffer.cursor_pos = cursor;
+        self.clamp_cursor();
+        self.notify_text_change();
+    }
+
+    /// Closes the current buffer, showing the next one (or a new empty one
+    /// if it was the last).
+    fn delete_buffer(&mut self, force: bool) -> Result<(), String> {
+        if self.buffer.is_modified() && !force {
+            return Err(format!(
+                "No write since last change for {} (add ! to override)",
+                self.buffer.name()
+            ));
+        }
+
+        let current = self.buffer.number;
+        let next = self
+            .hidden_buffers
+            .iter()
+            .position(|b| b.number > current)
+            .or_else(|| self.hidden_buffers.len().checked_sub(1));
+        self.buffer = match next {
+            Some(index) => self.hidden_buffers.remove(index),
+            None => {
+                self.next_buffer_number += 1;
+                Buffer::empty(self.next_buffer_number - 1)
+            }
+        };
+        let (deleted, replacement) = (current, self.buffer.number);
+        let other_tabs_windows = self.tabs.iter_mut().flat_map(|t| t.windows.iter_mut());
+        for w in self
+            .windows
+            .iter_mut()
+            .chain(other_tabs_windows)
+            .filter(|w| w.buffer == deleted)
+        {
+            w.buffer = replacement;
+        }
+        if self.pubsub.send(buffer_deleted_topic(), deleted).is_err() {
+            log::debug!("Buffer deleted but nobody's listening");
+        }
+        self.notify_text_change();
+        Ok(())
+    }
+
+    /// The first buffer, shown or not, with changes that haven't been saved.
+    fn modified_buffer(&self) -> Option<&Buffer> {
+        std::iter::once(&self.buffer)
+            .chain(self.hidden_buffers.iter())
+            .find(|b| b.is_modified())
+    }
+
+    fn list_buffers(&self) -> String {
+        let mut buffers: Vec<&Buffer> = self.hidden_buffers.iter().collect();
+        buffers.push(&self.buffer);
+        buffers.sort_by_key(|b| b.number);
+        buffers
+            .iter()
+            .map(|b| {
+                format!(
+                    "{}{}{} {}",
+                    b.number,
+                    if b.number == self.buffer.number {
+                        "%"
+                    } else {
+                        ""
+                    },
+                    if b.is_modified() { "+" } else { "" },
+                    b.name()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+
+    /// Applies the save-time cleanups that are turned on, as ordinary edits
+    /// of the buffer so that what's on screen matches what's written.
+    fn clean_up_for_save(&mut self) {
+        let mut changed = 0;
+        if self.options.trimtrailing {
+            changed += self
+                .buffer
+                .text
+                .strip_trailing_whitespace(self.buffer.saved_rev);
+        }
+        if self.options.fixeol {
+            changed += self.buffer.text.trim_trailing_blank_lines();
+        }
+        if self.options.retab {
+            let options = &self.options;
+            changed += self
+                .buffer
+                .text
+                .convert_indentation(options.tabstop, &|width| options.indent_string(width));
+        }
+
+        if changed > 0 {
+            self.clamp_cursor();
+            self.notify_text_change();
+        }
+    }
+
+    /// Runs the language's formatter over the whole buffer, applying its
+    /// output as an edit of just the lines that changed.
+    fn format(&mut self) -> Result<(), String> {
+        let formatter = match self.buffer.language.configured_formatter()? {
+            Some(formatter) => formatter,
+            None => return Ok(()),
+        };
+
+        let mut input = String::new();
+        for line in self.buffer.text.iter_lines() {
+            input.push_str(&line.content_str());
+            input.push('\n');
+        }
+        let output = shell::run_with_timeout(&formatter, Some(input), Some(FORMAT_TIMEOUT))?;
+        let formatted: Vec<String> = output.lines().map(|l| l.to_string()).collect();
+
+        let cursor_line = self
+            .buffer
+            .text
+            .line(self.buffer.cursor_pos.line_number)
+            .map(|l| l.id());
+        self.buffer.text.apply_lines(&formatted);
+        if let Some(ln) = cursor_line.and_then(|id| self.line_number_of(id)) {
+            self.buffer.cursor_pos.line_number = ln;
+        }
+        self.clamp_cursor();
+        self.notify_text_change();
+        Ok(())
+    }
+
+    fn write(&mut self) {
+        if let Some(f) = self.buffer.file.as_mut() {
+            f.seek(SeekFrom::Start(0))
+                .expect("seeking to start of file");
+            let num_lines = self.buffer.text.line_count();
+
+            let mut writer = BufWriter::new(f);
+            for (i, l) in self.buffer.text.iter_lines().enumerate() {
+                let write_result = writer.write_all(l.content_str().as_bytes()).and_then({
+                    |_| {
+                        if i < num_lines {
+                            writer.write(b"\n")
+                        } else {
+                            Ok(0)
+                        }
+                    }
+                });
+
+                if let Err(e) = write_result {
+                    self.status_text.clear();
+                    self.status_text
+                        .push_str(&format!("Failed to save file: {}", e));
+                    return;
+                }
+            }
+
+            let f = writer.get_mut();
+            let new_file_length = f
+                .stream_position()
+                .expect("Unable to determine length of file being written");
+            f.set_len(new_file_length)
+                .expect("Unable to truncate file after writing");
+            self.buffer.saved_rev = self.buffer.text.rev();
+        }
+    }
+
+    fn delete(&mut self) {
+        match self.mode {
+            Mode::Insert => {
+                // backspacing over a placeholder only removes the placeholder
+                if self.replace_pending_placeholder() {
+                    self.notify_text_change();
+                    return;
+                }
+                let cur_col = self.buffer.cursor_pos.colmun;
+                if cur_col > 0 {
+                    let to_delete = self.soft_tab_before_cursor().unwrap_or_else(|| {
+                        cur_col
+                            - self
+                                .buffer
+                                .text
+                                .prev_grapheme(self.buffer.cursor_pos.line_number, cur_col)
+                    });
+                    let delete_closing_pair = self.cursor_between_pair();
+                    let line = self
+                        .buffer
+                        .text
+                        .line_mut(self.buffer.cursor_pos.line_number);
+                    if let Some(line) = line {
+                        if delete_closing_pair {
+                            line.remove_char(self.buffer.cursor_pos.colmun);
+                        }
+                        for _ in 0..to_delete {
+                            line.remove_char(self.buffer.cursor_pos.colmun - 1);
+                            self.buffer.cursor_pos.colmun -= 1;
+                        }
+                    }
+                } else {
+                    // snippet fields are tracked within a line
+                    self.buffer.snippet_session = None;
+                    let cur_row = self.buffer.cursor_pos.line_number;
+
+                    if cur_row == 0 {
+                        if self.buffer.text.line_count() == 1
+                            && self
+                                .buffer
+                                .text
+                                .line(0)
+                                .expect("0th line missing")
+                                .char_count()
+                                == 0
+                        {
+                            self.buffer.text.remove_line(0);
+                        }
+                        return;
+                    }
+
+                    let end_of_prev_line = self
+                        .buffer
+                        .text
+                        .line(cur_row - 1)
+                        .map(|l| l.char_count())
+                        .unwrap_or(0);
+
+                    {
+                        let cur_line = self.buffer.text.remove_line(cur_row);
+                        if let Some(cur_line) = cur_line {
+                            let prev_row = self.buffer.text.line_mut(cur_row - 1);
+                            if let Some(prev_row) = prev_row {
                                 prev_row.extend_line(cur_line);
                             }
                         }
                     }
 
                     let new_row = cur_row - 1;
-                    self.cursor_pos.line_number = new_row;
-                    self.cursor_pos.colmun = end_of_prev_line;
+                    self.buffer.cursor_pos.line_number = new_row;
+                    self.buffer.cursor_pos.colmun = end_of_prev_line;
                 };
 
                 self.notify_text_change();
             }
-            Mode::Command => {
-                if self.command_line.len() > 0 {
-                    self.command_line.remove(self.command_line.len() - 1);
-                } else {
-                    self.shift_mode(Mode::Normal);
+            Mode::Command => {
+                if !self.command_line.is_empty() {
+                    self.command_line.remove(self.command_line.len() - 1);
+                } else {
+                    self.shift_mode(Mode::Normal);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn cursor_between_pair(&self) -> bool {
+        if !self.options.autopairs {
+            return false;
+        }
+        let line = match self.buffer.text.line(self.buffer.cursor_pos.line_number) {
+            Some(l) => l.content_string(),
+            None => return false,
+        };
+        let mut chars = line
+            .chars()
+            .skip(self.buffer.cursor_pos.colmun.wrapping_sub(1));
+        match (chars.next(), chars.next()) {
+            (Some(open), Some(close)) if self.buffer.cursor_pos.colmun > 0 => {
+                self.buffer.language.closing_pair(open) == Some(close)
+            }
+            _ => false,
+        }
+    }
+
+    /// When expanding tabs, backspacing through indentation removes a whole
+    /// shift width of spaces at a time. Returns how many spaces to remove.
+    fn soft_tab_before_cursor(&self) -> Option<usize> {
+        if !self.options.expandtab {
+            return None;
+        }
+        let line = self
+            .buffer
+            .text
+            .line(self.buffer.cursor_pos.line_number)?
+            .content_string();
+        let before: Vec<char> = line.chars().take(self.buffer.cursor_pos.colmun).collect();
+        if before.len() < 2 || before.iter().any(|c| *c != ' ') {
+            return None;
+        }
+        let sw = self.options.shiftwidth;
+        Some(match before.len() % sw {
+            0 => sw,
+            partial => partial,
+        })
+    }
+
+    fn notify_text_change(&mut self) {
+        if self
+            .pubsub
+            .send(
+                text_update_topic(),
+                TextUpdate {
+                    buffer: self.buffer.number,
+                    text: self.buffer.text.view(),
+                    language: self.buffer.language,
+                },
+            )
+            .is_err()
+        {
+            log::debug!("Text updated but nobody's listening");
+        }
+        self.notify_change();
+    }
+
+    pub fn status_text(&self) -> &str {
+        &self.status_text
+    }
+
+    pub fn mode(&self) -> &Mode {
+        &self.mode
+    }
+
+    pub fn shift_mode(&mut self, m: Mode) {
+        if m != Mode::Insert {
+            self.buffer.snippet_session = None;
+        }
+        self.mode = m;
+        self.command_line.clear();
+        self.notify_change();
+    }
+
+    /// The focused window's rows of text, and the columns beside its gutter.
+    fn view_size(&self) -> (usize, usize) {
+        let with_title = self.windows.len() > 1;
+        match self
+            .layout
+            .arrange(self.text_area())
+            .rect_of(self.focused_window)
+        {
+            Some(r) => {
+                let gutter = gutter::width(self.buffer.text.line_count(), &self.options);
+                (
+                    (r.height.saturating_sub(with_title as u16)).max(1) as usize,
+                    (r.width as usize).saturating_sub(gutter).max(1),
+                )
+            }
+            None => (1, 1),
+        }
+    }
+
+    /// How many rows line `n` takes up in a window `width` columns wide.
+    fn display_rows(&self, n: usize, width: usize) -> usize {
+        match self.buffer.text.line(n) {
+            Some(line) if self.options.wrap => {
+                wrap::row_starts(&line.content_string(), width, self.options.tabstop).len()
+            }
+            _ => 1,
+        }
+    }
+
+    fn top_line(&self) -> usize {
+        self.windows
+            .iter()
+            .find(|w| w.id == self.focused_window)
+            .map(|w| w.top_line)
+            .unwrap_or(0)
+    }
+
+    fn set_top_line(&mut self, top_line: usize) {
+        let focused = self.focused_window;
+        if let Some(w) = self.windows.iter_mut().find(|w| w.id == focused) {
+            w.top_line = top_line;
+        }
+    }
+
+    fn left_col(&self) -> usize {
+        self.windows
+            .iter()
+            .find(|w| w.id == self.focused_window)
+            .map(|w| w.left_col)
+            .unwrap_or(0)
+    }
+
+    fn set_left_col(&mut self, left_col: usize) {
+        let focused = self.focused_window;
+        if let Some(w) = self.windows.iter_mut().find(|w| w.id == focused) {
+            w.left_col = left_col;
+        }
+    }
+
+    /// The lowest line the cursor can be on with `top_line` at the top of
+    /// the focused window and `scrolloff` rows below it.
+    fn last_cursor_line(&self, top_line: usize) -> usize {
+        let (height, width) = self.view_size();
+        let margin = self.options.scrolloff.min(height.saturating_sub(1) / 2);
+        let last = self.buffer.text.line_count().saturating_sub(1);
+        let mut used = self.display_rows(top_line, width);
+        let mut line = top_line;
+        while line < last && used + self.display_rows(line + 1, width) + margin <= height {
+            line += 1;
+            used += self.display_rows(line, width);
+        }
+        line
+    }
+
+    /// Scrolls the focused window as little as it can to keep the cursor
+    /// `scrolloff` rows away from its top and bottom.
+    fn scroll_to_cursor(&mut self) {
+        let (height, width) = self.view_size();
+        let margin = self.options.scrolloff.min(height.saturating_sub(1) / 2);
+        let cursor = self.buffer.cursor_pos.line_number;
+        let line_count = self.buffer.text.line_count();
+
+        let mut top = self.top_line().min(cursor);
+        if cursor >= top + height {
+            top = cursor + 1 - height;
+        }
+
+        let mut above: usize = (top..cursor).map(|n| self.display_rows(n, width)).sum();
+        while top > 0 && above < margin {
+            top -= 1;
+            above += self.display_rows(top, width);
+        }
+
+        let below = (cursor + 1..line_count)
+            .take(margin)
+            .map(|n| self.display_rows(n, width))
+            .sum::<usize>()
+            .min(margin);
+        let mut used = above + self.display_rows(cursor, width);
+        while top < cursor && used + below > height {
+            used -= self.display_rows(top, width);
+            top += 1;
+        }
+
+        self.set_top_line(top);
+
+        let left = if self.options.wrap {
+            0
+        } else {
+            let line = self
+                .buffer
+                .text
+                .line(cursor)
+                .map(|l| l.content_string())
+                .unwrap_or_default();
+            let col =
+                columns::display_col(&line, self.buffer.cursor_pos.colmun, self.options.tabstop);
+            layout::scroll_to_show(self.left_col(), col, width, self.options.sidescrolloff)
+        };
+        self.set_left_col(left);
+    }
+
+    fn mouse(&mut self, c: Command) {
+        match c {
+            Command::Click { column, row } => self.click(column, row),
+            Command::Drag { column, row } => {
+                if self.selection_anchor.is_none() {
+                    self.selection_anchor = Some(self.buffer.cursor_pos.clone());
+                }
+                if let Some(pos) = self.position_at(column, row) {
+                    self.buffer.cursor_pos = pos;
+                    self.clamp_cursor();
+                }
+                self.notify_change();
+            }
+            Command::Wheel { column, row, lines } => {
+                if let Some((id, _)) = self.window_at(column, row) {
+                    self.focus_window(id);
                 }
+                self.scroll_view(lines);
             }
             _ => {}
         }
     }
 
-    fn notify_text_change(&mut self) {
-        if let Err(_) = self.pubsub.send(text_update_topic(), self.text.view()) {
-            log::debug!("Text updated but nobody's listening");
+    /// Moves the cursor to the text under the mouse, focusing the window or
+    /// tab page it's in.
+    fn click(&mut self, column: u16, row: u16) {
+        if row == 1 && self.tabs.len() > 1 {
+            if let Some(index) = self.tab_at(column) {
+                if index != self.current_tab {
+                    self.store_tab();
+                    self.load_tab(index);
+                }
+            }
+            return self.notify_change();
+        }
+
+        let (id, area) = match self.window_at(column, row) {
+            Some(window) => window,
+            None => return,
+        };
+        self.focus_window(id);
+        let on_title = self.windows.len() > 1 && row == area.y + area.height - 1;
+        if !on_title {
+            if let Some(pos) = self.position_at(column, row) {
+                self.buffer.cursor_pos = pos;
+                self.clamp_cursor();
+                self.selection_anchor = Some(self.buffer.cursor_pos.clone());
+            }
         }
         self.notify_change();
     }
 
-    pub fn status_text(&self) -> &str {
-        &self.status_text
+    /// The tab page whose label in the tab bar covers screen column `column`.
+    fn tab_at(&self, column: u16) -> Option<usize> {
+        let mut start = 1;
+        for (i, tab) in self.tab_views().iter().enumerate() {
+            let end = start + tab.label(i).width();
+            if (start..end).contains(&(column as usize)) {
+                return Some(i);
+            }
+            start = end;
+        }
+        None
     }
 
-    pub fn mode(&self) -> &Mode {
-        &self.mode
+    fn window_at(&self, column: u16, row: u16) -> Option<(WindowId, layout::Rect)> {
+        self.layout
+            .arrange(self.text_area())
+            .windows
+            .into_iter()
+            .find(|(_, area)| area.contains(column, row))
     }
 
-    pub fn shift_mode(&mut self, m: Mode) {
-        self.mode = m;
-        self.command_line.clear();
+    /// The position in the focused window's text shown at a screen cell,
+    /// looking through its gutter, scrolling and wrapping. Cells outside the
+    /// text go to the nearest position.
+    fn position_at(&self, column: u16, row: u16) -> Option<CursorPos> {
+        let area = self
+            .layout
+            .arrange(self.text_area())
+            .rect_of(self.focused_window)?;
+        let (height, width) = self.view_size();
+        let tabstop = self.options.tabstop;
+        let gutter = gutter::width(self.buffer.text.line_count(), &self.options);
+        let offset = (column.saturating_sub(area.x) as usize).saturating_sub(gutter);
+        let target_row = (row.saturating_sub(area.y) as usize).min(height - 1);
+
+        let last = self.buffer.text.line_count().saturating_sub(1);
+        let mut line_number = self.top_line().min(last);
+        let mut rows_above = 0;
+        while line_number < last {
+            let rows = self.display_rows(line_number, width);
+            if target_row < rows_above + rows {
+                break;
+            }
+            rows_above += rows;
+            line_number += 1;
+        }
+
+        let line = self
+            .buffer
+            .text
+            .line(line_number)
+            .map(|l| l.content_string())
+            .unwrap_or_default();
+        let colmun = if self.options.wrap {
+            let starts = wrap::row_starts(&line, width, tabstop);
+            let segment = target_row.saturating_sub(rows_above).min(starts.len() - 1);
+            wrap::col_in_row(&line, &starts, segment, offset, tabstop)
+        } else {
+            wrap::col_in_row(&line, &[0], 0, self.left_col() + offset, tabstop)
+        };
+        Some(CursorPos {
+            line_number,
+            colmun,
+        })
+    }
+
+    /// The ends of the selection, first one first, if the cursor has been
+    /// dragged away from where it started.
+    fn selection(&self) -> Option<(CursorPos, CursorPos)> {
+        let anchor = self.selection_anchor.clone()?;
+        let cursor = self.buffer.cursor_pos.clone();
+        let key = |p: &CursorPos| (p.line_number, p.colmun);
+        match key(&anchor).cmp(&key(&cursor)) {
+            std::cmp::Ordering::Less => Some((anchor, cursor)),
+            std::cmp::Ordering::Greater => Some((cursor, anchor)),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    fn scroll_view(&mut self, lines: isize) {
+        let last = self.buffer.text.line_count().saturating_sub(1);
+        let top = self.top_line().saturating_add_signed(lines).min(last);
+        self.set_top_line(top);
+
+        let (height, _) = self.view_size();
+        let margin = self.options.scrolloff.min(height.saturating_sub(1) / 2);
+        let first = if top == 0 {
+            0
+        } else {
+            (top + margin).min(last)
+        };
+        let cursor = self
+            .buffer
+            .cursor_pos
+            .line_number
+            .clamp(first, self.last_cursor_line(top).max(first));
+        self.move_cursor((
+            cursor as isize - self.buffer.cursor_pos.line_number as isize,
+            0,
+        ));
+        self.notify_change();
+    }
+
+    fn reposition(&mut self, anchor: ViewAnchor) {
+        let (height, width) = self.view_size();
+        let cursor = self.buffer.cursor_pos.line_number;
+        let space = height.saturating_sub(self.display_rows(cursor, width));
+        let above = match anchor {
+            ViewAnchor::Top => 0,
+            ViewAnchor::Middle => space / 2,
+            ViewAnchor::Bottom => space,
+        };
+
+        let mut top = cursor;
+        let mut used = 0;
+        while top > 0 && used + self.display_rows(top - 1, width) <= above {
+            top -= 1;
+            used += self.display_rows(top, width);
+        }
+        self.set_top_line(top);
+        self.notify_change();
+    }
+
+    /// Moves the cursor `rows` display rows down (or up, if negative),
+    /// keeping its position across the row where it can. Without `wrap`
+    /// this is the same as moving by lines.
+    pub fn move_display_line(&mut self, rows: isize) {
+        if !self.options.wrap {
+            return self.move_cursor((rows, 0));
+        }
+
+        let (_, width) = self.view_size();
+        let tabstop = self.options.tabstop;
+        let line_text =
+            |text: &Text, n: usize| text.line(n).map(|l| l.content_string()).unwrap_or_default();
+
+        let mut line_number = self.buffer.cursor_pos.line_number;
+        let mut line = line_text(&self.buffer.text, line_number);
+        let mut starts = wrap::row_starts(&line, width, tabstop);
+        let mut row = wrap::row_of(&starts, self.buffer.cursor_pos.colmun);
+        let offset = columns::display_col(&line, self.buffer.cursor_pos.colmun, tabstop)
+            - columns::display_col(&line, starts[row], tabstop);
+
+        for _ in 0..rows.unsigned_abs() {
+            if rows > 0 {
+                if row + 1 < starts.len() {
+                    row += 1;
+                } else if line_number + 1 < self.buffer.text.line_count() {
+                    line_number += 1;
+                    line = line_text(&self.buffer.text, line_number);
+                    starts = wrap::row_starts(&line, width, tabstop);
+                    row = 0;
+                }
+            } else if row > 0 {
+                row -= 1;
+            } else if line_number > 0 {
+                line_number -= 1;
+                line = line_text(&self.buffer.text, line_number);
+                starts = wrap::row_starts(&line, width, tabstop);
+                row = starts.len() - 1;
+            }
+        }
+
+        self.buffer.cursor_pos.line_number = line_number;
+        self.buffer.cursor_pos.colmun = wrap::col_in_row(&line, &starts, row, offset, tabstop);
         self.notify_change();
     }
 
@@ -357,37 +2736,38 @@ impl<'a> State {
         match direction {
             (0, 0) => {}
             (ln, 0) => {
-                self.cursor_pos.line_number = if !ln.is_negative() {
-                    self.cursor_pos.line_number.saturating_add(ln as usize)
+                self.buffer.cursor_pos.line_number = if !ln.is_negative() {
+                    self.buffer
+                        .cursor_pos
+                        .line_number
+                        .saturating_add(ln as usize)
                 } else {
-                    self.cursor_pos
+                    self.buffer
+                        .cursor_pos
                         .line_number
                         .saturating_sub(ln.saturating_abs() as usize)
                 }
-                .clamp(0, self.text.line_count().saturating_sub(1));
+                .clamp(0, self.buffer.text.line_count().saturating_sub(1));
 
-                let line = self.text.line(self.cursor_pos.line_number);
-                self.cursor_pos.colmun = line
-                    .map(|l| self.cursor_pos.colmun.clamp(0, l.char_count()))
+                let ln = self.buffer.cursor_pos.line_number;
+                let line = self.buffer.text.line(ln);
+                let col = line
+                    .map(|l| self.buffer.cursor_pos.colmun.clamp(0, l.char_count()))
                     .unwrap_or(0);
+                self.buffer.cursor_pos.colmun = self.buffer.text.grapheme_start(ln, col);
 
                 self.notify_change();
             }
             (0, col) => {
-                let line = self.text.line(self.cursor_pos.line_number);
-                if let Some(line) = line {
-                    if !col.is_negative() {
-                        self.cursor_pos.colmun = self
-                            .cursor_pos
-                            .colmun
-                            .saturating_add(col as usize)
-                            .clamp(0, line.char_count());
-                    } else {
-                        self.cursor_pos.colmun = self
-                            .cursor_pos
-                            .colmun
-                            .saturating_sub(col.abs() as usize)
-                            .clamp(0, line.char_count());
+                let ln = self.buffer.cursor_pos.line_number;
+                if self.buffer.text.line(ln).is_some() {
+                    for _ in 0..col.unsigned_abs() {
+                        let current = self.buffer.cursor_pos.colmun;
+                        self.buffer.cursor_pos.colmun = if !col.is_negative() {
+                            self.buffer.text.next_grapheme(ln, current)
+                        } else {
+                            self.buffer.text.prev_grapheme(ln, current)
+                        };
                     }
 
                     self.notify_change();
@@ -400,15 +2780,19 @@ impl<'a> State {
             }
         };
 
-        assert!(self.cursor_pos.line_number <= self.text.line_count());
-        if self.cursor_pos.line_number < self.text.line_count() {
-            let line = &self.text.line(self.cursor_pos.line_number).unwrap();
-            assert!(self.cursor_pos.colmun <= line.char_count());
+        assert!(self.buffer.cursor_pos.line_number <= self.buffer.text.line_count());
+        if self.buffer.cursor_pos.line_number < self.buffer.text.line_count() {
+            let line = &self
+                .buffer
+                .text
+                .line(self.buffer.cursor_pos.line_number)
+                .unwrap();
+            assert!(self.buffer.cursor_pos.colmun <= line.char_count());
         }
     }
 
     pub fn cursor_pos(&self) -> &CursorPos {
-        &self.cursor_pos
+        &self.buffer.cursor_pos
     }
 
     pub fn command_line(&self) -> &str {
@@ -416,55 +2800,938 @@ impl<'a> State {
     }
 
     pub fn text(&self) -> &Text {
-        &self.text
+        &self.buffer.text
     }
 }
 
-pub fn empty<'a>(pubsub: Hub) -> State {
+pub fn empty(pubsub: Hub) -> State {
+    let window = WindowId::default();
+    let tab = TabPage {
+        layout: Layout::Window(window),
+        windows: vec![Window {
+            id: window,
+            buffer: 1,
+            cursor_pos: CursorPos {
+                line_number: 0,
+                colmun: 0,
+            },
+            top_line: 0,
+            left_col: 0,
+        }],
+        focused_window: window,
+    };
     State {
-        cursor_pos: CursorPos {
-            line_number: 0,
-            colmun: 0,
-        },
-        text: Text::new(),
+        buffer: Buffer::empty(1),
+        hidden_buffers: Vec::new(),
+        next_buffer_number: 2,
+        layout: tab.layout.clone(),
+        windows: tab.windows.clone(),
+        focused_window: window,
+        next_window_id: window.next(),
+        tabs: vec![tab],
+        current_tab: 0,
+        screen_size: (80, 24),
+        selection_anchor: None,
         status_text: String::new(),
         mode: Mode::Normal,
         command_line: String::new(),
-        file: None,
+        options: Options::default(),
+        colorscheme: theme::DEFAULT.to_string(),
         pubsub,
     }
 }
 
-pub fn from_file(fname: &OsStr, pubsub: Hub) -> io::Result<State> {
-    println!("opening {:?}", fname);
-
-    let f = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(fname)?;
-    let reader = BufReader::new(f.try_clone()?);
-    let mut lines = Vec::new();
+fn ex_line_op(c: &ExCommand) -> Option<LineOp> {
+    let op = if c.is("d", "delete") {
+        LineOp::Delete
+    } else if c.is("dup", "duplicate") {
+        LineOp::Duplicate
+    } else if c.is("moveu", "moveup") {
+        LineOp::MoveUp
+    } else if c.is("moved", "movedown") {
+        LineOp::MoveDown
+    } else if c.is("j", "join") {
+        LineOp::Join
+    } else if c.is("up", "upper") {
+        LineOp::ChangeCase(Case::Upper)
+    } else if c.is("lo", "lower") {
+        LineOp::ChangeCase(Case::Lower)
+    } else if c.is("ti", "title") {
+        LineOp::ChangeCase(Case::Title)
+    } else if c.is("rev", "reverse") {
+        LineOp::Reverse
+    } else if c.is("uniq", "unique") {
+        LineOp::Unique
+    } else if c.is("com", "comment") {
+        LineOp::ToggleComment
+    } else {
+        return None;
+    };
+    Some(op)
+}
 
-    for l in reader.lines() {
-        let l = l?;
-        lines.push(l);
+fn snippet_variable(
+    name: &str,
+    path: Option<&Path>,
+    line_number: usize,
+    current_line: &str,
+) -> Option<String> {
+    let file_name = || {
+        path.and_then(|p| p.file_name())
+            .map(|f| f.to_string_lossy())
+    };
+    match name {
+        "TM_FILENAME" => file_name().map(|f| f.to_string()),
+        "TM_FILENAME_BASE" => path
+            .and_then(|p| p.file_stem())
+            .map(|f| f.to_string_lossy().to_string()),
+        "TM_FILEPATH" => path.map(|p| p.display().to_string()),
+        "TM_DIRECTORY" => path
+            .and_then(|p| p.parent())
+            .map(|p| p.display().to_string()),
+        "TM_LINE_INDEX" => Some(line_number.to_string()),
+        "TM_LINE_NUMBER" => Some((line_number + 1).to_string()),
+        "TM_CURRENT_LINE" => Some(current_line.to_string()),
+        _ => None,
     }
+}
 
-    let mut result = State {
-        cursor_pos: CursorPos {
-            line_number: 0,
-            colmun: 0,
-        },
-        text: Text::from(&lines),
-        status_text: String::new(),
-        mode: Mode::Normal,
-        command_line: String::new(),
-        file: Some(f),
-        pubsub: pubsub,
-    };
+/// Opens each of `fnames` in its own buffer, showing the first.
+pub fn from_files(fnames: &[OsString], pubsub: Hub) -> io::Result<State> {
+    let mut result = empty(pubsub);
+
+    for (i, fname) in fnames.iter().enumerate() {
+        println!("opening {:?}", fname);
+        let (buffer, status_text) = Buffer::open(fname, i + 1)?;
+        if i == 0 {
+            result.buffer = buffer;
+        } else {
+            result.hidden_buffers.push(buffer);
+        }
+        if !status_text.is_empty() {
+            result.status_text = status_text;
+        }
+        result.next_buffer_number = i + 2;
+    }
 
     result.notify_text_change();
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::userinput;
+    use termion::input::TermReadEventsAndRaw;
+
+    fn state_with(lines: &[&str], language: &'static Language) -> State {
+        let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        let mut state = empty(Hub::new());
+        state.buffer.text = Text::from(&lines);
+        state.buffer.language = language;
+        state
+    }
+
+    fn lines_of(state: &State) -> Vec<String> {
+        state
+            .text()
+            .iter_lines()
+            .map(|l| l.content_str().to_string())
+            .collect()
+    }
+
+    fn type_text(state: &mut State, s: &str) {
+        for c in s.chars() {
+            state.dispatch(Command::InsertAtCursor(c));
+        }
+    }
+
+    #[test]
+    fn new_line_indents_after_opening_brace() {
+        let mut state = state_with(&["    fn main() {"], language::for_path(Path::new("a.rs")));
+        state.options.expandtab = true;
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos.colmun = 15;
+
+        type_text(&mut state, "\nx");
+
+        assert_eq!(lines_of(&state), vec!["    fn main() {", "        x"]);
+        assert_eq!(state.cursor_pos().line_number, 1);
+        assert_eq!(state.cursor_pos().colmun, 9);
+    }
+
+    #[test]
+    fn new_line_between_brackets_puts_closer_on_its_own_line() {
+        let mut state = state_with(&["  foo()"], language::for_path(Path::new("a.rs")));
+        state.options.expandtab = true;
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos.colmun = 6;
+
+        type_text(&mut state, "\n");
+
+        assert_eq!(lines_of(&state), vec!["  foo(", "      ", "  )"]);
+        assert_eq!(state.cursor_pos().colmun, 6);
+    }
+
+    #[test]
+    fn closing_brace_dedents_to_matching_opener() {
+        let mut state = state_with(
+            &["fn main() {", "    if x {", "        y();", "        "],
+            language::for_path(Path::new("a.rs")),
+        );
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos = CursorPos {
+            line_number: 3,
+            colmun: 8,
+        };
+
+        type_text(&mut state, "}");
+
+        assert_eq!(lines_of(&state)[3], "    }");
+        assert_eq!(state.cursor_pos().colmun, 5);
+    }
+
+    #[test]
+    fn tab_inserts_spaces_to_next_shift_width_when_expanding() {
+        let mut state = state_with(&["ab"], language::plain_text());
+        state.options.expandtab = true;
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos.colmun = 2;
+
+        type_text(&mut state, "\t");
+        assert_eq!(lines_of(&state), vec!["ab  "]);
+
+        state.options.expandtab = false;
+        type_text(&mut state, "\t");
+        assert_eq!(lines_of(&state), vec!["ab  \t"]);
+    }
+
+    #[test]
+    fn backspace_removes_soft_tab_in_indentation() {
+        let mut state = state_with(&["      x"], language::plain_text());
+        state.options.expandtab = true;
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos.colmun = 6;
+
+        state.dispatch(Command::DeleteAtCursor);
+        assert_eq!(lines_of(&state), vec!["    x"]);
+        state.dispatch(Command::DeleteAtCursor);
+        assert_eq!(lines_of(&state), vec!["x"]);
+        assert_eq!(state.cursor_pos().colmun, 0);
+    }
+
+    #[test]
+    fn shift_lines_indents_and_dedents_by_shift_width() {
+        let mut state = state_with(&["  x", ""], language::plain_text());
+        state.buffer.cursor_pos.colmun = 2;
+
+        let mut pending = Vec::new();
+        assert!(input_map(&Mode::Normal, &mut pending, Event::Key(Key::Char('>'))).is_none());
+        let c = input_map(&Mode::Normal, &mut pending, Event::Key(Key::Char('>'))).unwrap();
+        assert!(pending.is_empty());
+
+        state.dispatch(c);
+        assert_eq!(lines_of(&state), vec!["    x", ""]);
+        assert_eq!(state.cursor_pos().colmun, 4);
+
+        state.options.expandtab = false;
+        state.options.tabstop = 4;
+        state.dispatch(Command::ShiftLines(1));
+        assert_eq!(lines_of(&state), vec!["\t\tx", ""]);
+
+        state.dispatch(Command::ShiftLines(-1));
+        state.dispatch(Command::ShiftLines(-1));
+        state.dispatch(Command::ShiftLines(-1));
+        assert_eq!(lines_of(&state), vec!["x", ""]);
+        assert_eq!(state.cursor_pos().colmun, 0);
+    }
+
+    #[test]
+    fn brackets_and_quotes_are_closed_and_overtyped() {
+        let mut state = state_with(&[""], language::for_path(Path::new("a.py")));
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+
+        type_text(&mut state, "f(\"x");
+        assert_eq!(lines_of(&state), vec!["f(\"x\")"]);
+        type_text(&mut state, "\")");
+        assert_eq!(lines_of(&state), vec!["f(\"x\")"]);
+        assert_eq!(state.cursor_pos().colmun, 6);
+    }
+
+    #[test]
+    fn brackets_in_unhighlighted_strings_are_not_paired() {
+        let mut state = state_with(&[""], language::for_path(Path::new("a.rs")));
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+
+        type_text(&mut state, "f(\"a(");
+        assert_eq!(lines_of(&state), vec!["f(\"a(\")"]);
+    }
+
+    #[test]
+    fn overtyped_closing_brace_is_reindented() {
+        let rust = language::for_path(Path::new("a.rs"));
+        let mut state = state_with(&["fn f() {", "        }"], rust);
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos = CursorPos {
+            line_number: 1,
+            colmun: 8,
+        };
+
+        type_text(&mut state, "}");
+        assert_eq!(lines_of(&state), vec!["fn f() {", "}"]);
+        assert_eq!(state.cursor_pos().colmun, 1);
+    }
+
+    #[test]
+    fn quotes_are_not_paired_after_word_characters() {
+        let mut state = state_with(&[""], language::for_path(Path::new("a.py")));
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+
+        type_text(&mut state, "don't");
+        assert_eq!(lines_of(&state), vec!["don't"]);
+
+        let mut state = state_with(&[""], language::for_path(Path::new("a.rs")));
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        type_text(&mut state, "&'a");
+        assert_eq!(lines_of(&state), vec!["&'a"]);
+    }
+
+    #[test]
+    fn backspace_between_pair_removes_both() {
+        let mut state = state_with(&[""], language::for_path(Path::new("a.rs")));
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+
+        type_text(&mut state, "x[");
+        assert_eq!(lines_of(&state), vec!["x[]"]);
+        state.dispatch(Command::DeleteAtCursor);
+        assert_eq!(lines_of(&state), vec!["x"]);
+        assert_eq!(state.cursor_pos().colmun, 1);
+    }
+
+    #[test]
+    fn snippet_expands_and_tabs_through_fields_updating_mirrors() {
+        let mut state = state_with(&["  x"], language::plain_text());
+        state.options.expandtab = true;
+        state.buffer.snippets = snippet::parse_snippets(
+            r#"{ "for": { "prefix": "for", "body": ["for ${1:i} in $2 {", "\t$1$0", "}"] } }"#,
+        )
+        .unwrap();
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos.colmun = 2;
+
+        type_text(&mut state, "for");
+        state.dispatch(Command::NextTabStop);
+        assert_eq!(lines_of(&state), vec!["  for i in  {", "      i", "  }x"]);
+        assert_eq!(state.cursor_pos().colmun, 6);
+
+        type_text(&mut state, "idx");
+        state.dispatch(Command::NextTabStop);
+        assert_eq!(state.cursor_pos().colmun, 13);
+        type_text(&mut state, "xs");
+        state.dispatch(Command::NextTabStop);
+
+        assert_eq!(
+            lines_of(&state),
+            vec!["  for idx in xs {", "      idx", "  }x"]
+        );
+        assert_eq!(state.cursor_pos().line_number, 1);
+        assert_eq!(state.cursor_pos().colmun, 9);
+        assert!(state.buffer.snippet_session.is_none());
+
+        state.dispatch(Command::NextTabStop);
+        assert_eq!(lines_of(&state)[1], "      idx   ");
+    }
+
+    #[test]
+    fn snippet_choices_cycle() {
+        let mut state = state_with(&[""], language::plain_text());
+        state.buffer.snippets =
+            snippet::parse_snippets(r#"{ "c": { "prefix": "c", "body": "${1|a,bb|};" } }"#)
+                .unwrap();
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+
+        type_text(&mut state, "c");
+        state.dispatch(Command::NextTabStop);
+        assert_eq!(lines_of(&state), vec!["a;"]);
+        state.dispatch(Command::CycleChoice);
+        assert_eq!(lines_of(&state), vec!["bb;"]);
+        state.dispatch(Command::PrevTabStop);
+        state.dispatch(Command::NextTabStop);
+        assert_eq!(state.cursor_pos().colmun, 3);
+    }
+
+    #[test]
+    fn snippet_fields_handle_backspace_and_autopairs() {
+        let mut state = state_with(&[""], language::plain_text());
+        state.buffer.snippets = snippet::parse_snippets(
+            r#"{ "p": { "prefix": "p", "body": ["f(${1:arg});", "$1"] } }"#,
+        )
+        .unwrap();
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+
+        type_text(&mut state, "p");
+        state.dispatch(Command::NextTabStop);
+        assert_eq!(lines_of(&state), vec!["f(arg);", "arg"]);
+        state.dispatch(Command::DeleteAtCursor);
+        assert_eq!(lines_of(&state), vec!["f();", "arg"]);
+        assert_eq!(state.cursor_pos().colmun, 2);
+
+        type_text(&mut state, "g(");
+        assert_eq!(lines_of(&state)[0], "f(g());");
+        state.dispatch(Command::NextTabStop);
+        assert_eq!(lines_of(&state), vec!["f(g());", "g()"]);
+    }
+
+    #[test]
+    fn line_breaks_in_a_field_end_the_snippet() {
+        let mut state = state_with(&["", "z"], language::plain_text());
+        state.buffer.snippets =
+            snippet::parse_snippets(r#"{ "p": { "prefix": "p", "body": "f(${1:a}, ${2:b})" } }"#)
+                .unwrap();
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+
+        type_text(&mut state, "p");
+        state.dispatch(Command::NextTabStop);
+        type_text(&mut state, "x\ny");
+        assert!(state.buffer.snippet_session.is_none());
+        state.dispatch(Command::NextTabStop);
+        assert_eq!(lines_of(&state)[0], "f(x");
+        assert!(lines_of(&state)[1].starts_with('y'));
+        assert!(lines_of(&state)[1].ends_with(", b)"));
+        assert_eq!(state.cursor_pos().line_number, 1);
+
+        type_text(&mut state, " p");
+        state.dispatch(Command::NextTabStop);
+        assert!(state.buffer.snippet_session.is_some());
+        state.paste("1\n2");
+        assert!(state.buffer.snippet_session.is_none());
+    }
+
+    fn run_command(state: &mut State, command: &str) {
+        state.dispatch(Command::ShiftMode(Mode::Command));
+        type_text(state, command);
+        state.dispatch(Command::CommitCommandline);
+    }
+
+    #[test]
+    fn line_ops_from_keys_and_command_line() {
+        let mut state = state_with(&["c", "a", "b", "a"], language::plain_text());
+
+        state.dispatch(Command::LineOp(LineOp::MoveDown));
+        assert_eq!(lines_of(&state), vec!["a", "c", "b", "a"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 1);
+
+        run_command(&mut state, "%sort");
+        assert_eq!(lines_of(&state), vec!["a", "a", "b", "c"]);
+
+        run_command(&mut state, "%uniq");
+        assert_eq!(lines_of(&state), vec!["a", "b", "c"]);
+
+        run_command(&mut state, "2,$upper");
+        assert_eq!(lines_of(&state), vec!["a", "B", "C"]);
+
+        run_command(&mut state, "1,2d");
+        assert_eq!(lines_of(&state), vec!["C"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 0);
+    }
+
+    #[test]
+    fn ranged_line_ops_leave_a_cursor_outside_the_range() {
+        let mut state = state_with(&["a", "b", "c", "d"], language::plain_text());
+
+        run_command(&mut state, "3moveup");
+        assert_eq!(lines_of(&state), vec!["a", "c", "b", "d"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 0);
+
+        run_command(&mut state, "2,3dup");
+        assert_eq!(lines_of(&state), vec!["a", "c", "b", "c", "b", "d"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 0);
+
+        state.buffer.cursor_pos.line_number = 1;
+        run_command(&mut state, "2movedown");
+        assert_eq!(lines_of(&state), vec!["a", "b", "c", "c", "b", "d"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 2);
+    }
+
+    #[test]
+    fn bad_ex_commands_report_in_status_line() {
+        let mut state = state_with(&["a"], language::plain_text());
+        run_command(&mut state, "5d");
+        assert_eq!(lines_of(&state), vec!["a"]);
+        assert!(!state.status_text.is_empty());
+
+        run_command(&mut state, "frobnicate");
+        assert!(state.status_text.contains("frobnicate"));
+    }
+
+    #[test]
+    fn ctrl_w_deletes_word_before_cursor() {
+        let mut state = state_with(&["foo bar"], language::plain_text());
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos.colmun = 7;
+        state.dispatch(Command::DeleteWordBackward);
+        assert_eq!(lines_of(&state), vec!["foo "]);
+        assert_eq!(state.buffer.cursor_pos.colmun, 4);
+    }
+
+    #[test]
+    fn toggles_comments_with_language_markers() {
+        let rust = language::for_path(Path::new("a.rs"));
+        let mut state = state_with(&["fn f() {", "    x();", "}"], rust);
+        state.buffer.cursor_pos.line_number = 1;
+        state.buffer.cursor_pos.colmun = 4;
+
+        state.dispatch(Command::LineOp(LineOp::ToggleComment));
+        assert_eq!(lines_of(&state), vec!["fn f() {", "    // x();", "}"]);
+        assert_eq!(state.buffer.cursor_pos.colmun, 7);
+
+        run_command(&mut state, "%comment");
+        assert_eq!(
+            lines_of(&state),
+            vec!["// fn f() {", "//     // x();", "// }"]
+        );
+        run_command(&mut state, "%comment");
+        run_command(&mut state, "comment");
+        assert_eq!(lines_of(&state), vec!["fn f() {", "    x();", "}"]);
+
+        let mut state = state_with(&["a"], language::plain_text());
+        state.dispatch(Command::LineOp(LineOp::ToggleComment));
+        assert_eq!(lines_of(&state), vec!["a"]);
+        assert!(!state.status_text.is_empty());
+    }
+
+    #[test]
+    fn filters_ranges_through_commands() {
+        let mut state = state_with(&["x", "c", "a", "b"], language::plain_text());
+
+        run_command(&mut state, "2,$!sort");
+        assert_eq!(lines_of(&state), vec!["x", "a", "b", "c"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 1);
+
+        run_command(&mut state, "%!echo failed >&2; exit 1");
+        assert_eq!(lines_of(&state), vec!["x", "a", "b", "c"]);
+        assert!(state.status_text.contains("failed"));
+
+        let first_line = state.buffer.text.line(0).unwrap().id();
+        run_command(&mut state, "2,3!tr a-z A-Z");
+        assert_eq!(lines_of(&state), vec!["x", "A", "B", "c"]);
+        assert_eq!(state.buffer.text.line(0).unwrap().id(), first_line);
+
+        run_command(&mut state, "%!true");
+        assert_eq!(lines_of(&state), vec![""]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 0);
+
+        run_command(&mut state, "!echo hello");
+        assert_eq!(state.status_text, "hello");
+    }
+
+    #[test]
+    fn formats_on_save_keeping_unchanged_lines() {
+        // indents to four spaces, spaces out `=` and rejects a bare `fn (`
+        let formatter = language::formatted_by(
+            "awk '/^fn \\(/ { exit 1 } { sub(/^ +/, \"    \"); gsub(/=/, \" = \"); print }'",
+        );
+        let mut state = state_with(&["fn main() {", "  let x=1;", "    x", "}"], formatter);
+        state.buffer.cursor_pos.line_number = 3;
+        let last_line = state.buffer.text.line(3).unwrap().id();
+
+        run_command(&mut state, "set formatonsave");
+        run_command(&mut state, "w");
+        assert_eq!(
+            lines_of(&state),
+            vec!["fn main() {", "    let x = 1;", "    x", "}"]
+        );
+        assert_eq!(state.buffer.text.line(3).unwrap().id(), last_line);
+        assert_eq!(state.buffer.cursor_pos.line_number, 3);
+
+        run_command(&mut state, "set trimtrailing");
+        state.buffer.text.insert_line(0, "fn ( ");
+        run_command(&mut state, "w");
+        assert_eq!(
+            state.buffer.text.line(0).unwrap().content_string().as_str(),
+            "fn ( "
+        );
+        assert!(state.status_text.starts_with("Not saved"));
+    }
+
+    #[test]
+    fn save_cleanups_are_buffer_edits() {
+        let mut state = state_with(&["a ", "\tb", "c ", "", ""], language::plain_text());
+        state.options.expandtab = true;
+        state.buffer.saved_rev = state.buffer.text.rev();
+        state.buffer.cursor_pos.line_number = 2;
+        state.buffer.cursor_pos.colmun = 1;
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        type_text(&mut state, "x");
+        state.dispatch(Command::ShiftMode(Mode::Normal));
+
+        run_command(&mut state, "set trimtrailing fixeol retab ts=4");
+        run_command(&mut state, "w");
+        assert_eq!(lines_of(&state), vec!["a ", "    b", "cx"]);
+    }
+
+    fn temp_file(name: &str, content: &str) -> OsString {
+        let dir = std::env::temp_dir().join(format!("jete-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path.into_os_string()
+    }
+
+    #[test]
+    fn switches_between_buffers() {
+        let a = temp_file("a.txt", "a1\na2\n");
+        let b = temp_file("b.txt", "b1\n");
+        let mut state = from_files(&[a, b], Hub::new()).unwrap();
+        assert_eq!(lines_of(&state), vec!["a1", "a2"]);
+        state.buffer.cursor_pos.line_number = 1;
+
+        run_command(&mut state, "bn");
+        assert_eq!(lines_of(&state), vec!["b1"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 0);
+        state.dispatch(Command::LineOp(LineOp::Duplicate));
+
+        run_command(&mut state, "bn");
+        assert_eq!(lines_of(&state), vec!["a1", "a2"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 1);
+
+        run_command(&mut state, "ls");
+        assert!(
+            state.status_text.starts_with("1% "),
+            "{}",
+            state.status_text
+        );
+        assert!(state.status_text.contains("2+ "), "{}", state.status_text);
+
+        run_command(&mut state, "b 2");
+        run_command(&mut state, "bd");
+        assert_eq!(lines_of(&state), vec!["b1", "b1"]);
+        assert!(state.status_text.contains("add !"));
+
+        run_command(&mut state, "bd!");
+        assert_eq!(lines_of(&state), vec!["a1", "a2"]);
+        run_command(&mut state, "bp");
+        assert_eq!(state.buffer.number, 1);
+
+        let c = temp_file("c.txt", "c1\n");
+        run_command(&mut state, &format!("e {}", c.to_str().unwrap()));
+        assert_eq!(lines_of(&state), vec!["c1"]);
+        assert_eq!(state.buffer.number, 3);
+
+        run_command(&mut state, "b 1");
+        let dir = Path::new(&c).parent().unwrap();
+        let c_again = dir.join(".").join("c.txt");
+        run_command(&mut state, &format!("e {}", c_again.to_str().unwrap()));
+        assert_eq!(state.buffer.number, 3);
+        assert_eq!(state.next_buffer_number, 4);
+    }
+
+    #[test]
+    fn quit_refuses_with_unsaved_hidden_buffers() {
+        let a = temp_file("quit-a.txt", "a1\n");
+        let b = temp_file("quit-b.txt", "b1\n");
+        let mut state = from_files(&[a, b], Hub::new()).unwrap();
+        state.dispatch(Command::LineOp(LineOp::Duplicate));
+        run_command(&mut state, "bn");
+        assert!(state.hidden_buffers[0].is_modified());
+
+        state.dispatch(Command::ShiftMode(Mode::Command));
+        type_text(&mut state, "q");
+        assert!(matches!(
+            state.dispatch(Command::CommitCommandline),
+            EditorAction::None
+        ));
+        assert!(
+            state
+                .status_text
+                .starts_with("No write since last change (buffer 1)"),
+            "{}",
+            state.status_text
+        );
+
+        state.dispatch(Command::ShiftMode(Mode::Command));
+        type_text(&mut state, "q!");
+        assert!(matches!(
+            state.dispatch(Command::CommitCommandline),
+            EditorAction::Quit
+        ));
+    }
+
+    #[test]
+    fn split_windows_keep_their_own_cursor_and_buffer() {
+        let a = temp_file("split-a.txt", "a1\na2\na3\n");
+        let b = temp_file("split-b.txt", "b1\n");
+        let mut state = from_files(&[a], Hub::new()).unwrap();
+        state.buffer.cursor_pos.line_number = 2;
+
+        run_command(&mut state, &format!("vsplit {}", b.to_str().unwrap()));
+        assert_eq!(state.windows.len(), 2);
+        assert_eq!(lines_of(&state), vec!["b1"]);
+
+        state.dispatch(Command::Window(WindowCommand::Focus(Side::Right)));
+        assert_eq!(lines_of(&state), vec!["a1", "a2", "a3"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 2);
+
+        state.dispatch(Command::Window(WindowCommand::Split(Orientation::Stacked)));
+        state.buffer.cursor_pos.line_number = 0;
+        state.dispatch(Command::Window(WindowCommand::Focus(Side::Down)));
+        assert_eq!(state.buffer.cursor_pos.line_number, 2);
+
+        run_command(&mut state, "resize -3");
+        let area = state.text_area();
+        let rect = state.layout.arrange(area).rect_of(state.focused_window);
+        assert_eq!(rect.map(|r| r.height), Some(8));
+
+        run_command(&mut state, "close");
+        assert_eq!(state.buffer.cursor_pos.line_number, 0);
+        run_command(&mut state, "q");
+        run_command(&mut state, "clo");
+        assert_eq!(lines_of(&state), vec!["b1"]);
+        assert!(state.status_text.contains("last window"));
+    }
+
+    #[test]
+    fn tab_pages_keep_their_own_windows() {
+        let a = temp_file("tab-a.txt", "a1\na2\n");
+        let b = temp_file("tab-b.txt", "b1\n");
+        let mut state = from_files(&[a], Hub::new()).unwrap();
+        state.buffer.cursor_pos.line_number = 1;
+        run_command(&mut state, "vsplit");
+
+        run_command(&mut state, &format!("tabnew {}", b.to_str().unwrap()));
+        assert_eq!(lines_of(&state), vec!["b1"]);
+        assert_eq!(state.windows.len(), 1);
+        let current: Vec<bool> = state.tab_views().iter().map(|t| t.current).collect();
+        assert_eq!(current, vec![false, true]);
+
+        state.dispatch(Command::NextTab(true));
+        assert_eq!(state.windows.len(), 2);
+        assert_eq!(lines_of(&state), vec!["a1", "a2"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 1);
+        assert_eq!(state.tab_views()[1].name, "tab-b.txt");
+
+        state.dispatch(Command::NextTab(false));
+        run_command(&mut state, "tabnew");
+        assert_eq!(state.tabs.len(), 3);
+        assert_eq!(state.current_tab, 2);
+        assert_eq!(lines_of(&state), Vec::<String>::new());
+
+        run_command(&mut state, "q");
+        assert_eq!(state.tabs.len(), 2);
+        assert_eq!(lines_of(&state), vec!["b1"]);
+        run_command(&mut state, "tabclose");
+        assert_eq!(lines_of(&state), vec!["a1", "a2"]);
+        assert_eq!(state.windows.len(), 2);
+        run_command(&mut state, "tabclose");
+        assert!(state.status_text.contains("last tab"));
+    }
+
+    #[test]
+    fn display_line_motion_moves_through_wrapped_rows() {
+        let mut state = state_with(
+            &["aaaa bbbb cccc dddd eeee ffff", "xy"],
+            language::plain_text(),
+        );
+        state.resized((26, 24));
+        state.buffer.cursor_pos.colmun = 2;
+
+        state.dispatch(Command::MoveDisplayLine(1));
+        assert_eq!(state.buffer.cursor_pos.line_number, 1);
+
+        state.dispatch(Command::MoveDisplayLine(-1));
+        run_command(&mut state, "set wrap");
+        state.dispatch(Command::MoveDisplayLine(1));
+        assert_eq!(state.buffer.cursor_pos.line_number, 0);
+        assert_eq!(state.buffer.cursor_pos.colmun, 22);
+
+        state.dispatch(Command::MoveDisplayLine(1));
+        assert_eq!(state.buffer.cursor_pos.line_number, 1);
+        assert_eq!(state.buffer.cursor_pos.colmun, 2);
+
+        state.dispatch(Command::MoveDisplayLine(-2));
+        assert_eq!(state.buffer.cursor_pos.line_number, 0);
+        assert_eq!(state.buffer.cursor_pos.colmun, 2);
+    }
+
+    #[test]
+    fn bookmarks_are_published_as_signs() {
+        let mut hub = Hub::new();
+        let signs = hub.get_receiver(signs::topic());
+        let mut state = empty(hub);
+        state.buffer.text = Text::from(&["a".to_string(), "b".to_string()]);
+        let ids: Vec<LineId> = state.text().iter_lines().map(|l| l.id()).collect();
+
+        state.dispatch(Command::ToggleBookmark);
+        let mut column = signs::SignColumn::default();
+        column.update(signs.try_recv().unwrap());
+        assert_eq!(
+            column.sign_for(ids[0]).map(|s| s.kind()),
+            Some(SignKind::Bookmark)
+        );
+
+        state.dispatch(Command::LineOp(LineOp::MoveDown));
+        state.dispatch(Command::ToggleBookmark);
+        column.update(signs.try_recv().unwrap());
+        assert_eq!(column.sign_for(ids[0]), None);
+    }
+
+    #[test]
+    fn viewport_keeps_scrolloff_and_repositions() {
+        let lines: Vec<String> = (0..100).map(|n| n.to_string()).collect();
+        let lines: Vec<&str> = lines.iter().map(|l| l.as_str()).collect();
+        let mut state = state_with(&lines, language::plain_text());
+        state.resized((80, 12));
+
+        state.move_cursor((20, 0));
+        assert_eq!(state.top_line(), 15);
+
+        state.dispatch(Command::Reposition(ViewAnchor::Top));
+        assert_eq!(state.top_line(), 16);
+        state.dispatch(Command::Reposition(ViewAnchor::Bottom));
+        assert_eq!(state.top_line(), 15);
+        state.dispatch(Command::Reposition(ViewAnchor::Middle));
+        assert_eq!(state.top_line(), 16);
+
+        state.dispatch(Command::Scroll(1));
+        assert_eq!(state.top_line(), 17);
+        assert_eq!(state.buffer.cursor_pos.line_number, 21);
+
+        state.dispatch(Command::Scroll(-2));
+        assert_eq!(state.top_line(), 15);
+        assert_eq!(state.buffer.cursor_pos.line_number, 20);
+
+        state.move_cursor((-20, 0));
+        assert_eq!(state.top_line(), 0);
+    }
+
+    #[test]
+    fn cursor_moves_and_deletes_by_grapheme() {
+        let mut state = state_with(&["e\u{301}日x", "ab"], language::plain_text());
+        state.move_cursor((0, 1));
+        assert_eq!(state.buffer.cursor_pos.colmun, 2);
+        state.move_cursor((0, 1));
+        assert_eq!(state.buffer.cursor_pos.colmun, 3);
+
+        state.buffer.cursor_pos.colmun = 1;
+        state.move_cursor((1, 0));
+        state.move_cursor((-1, 0));
+        assert_eq!(state.buffer.cursor_pos.colmun, 0);
+
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos.colmun = 2;
+        state.dispatch(Command::DeleteAtCursor);
+        assert_eq!(lines_of(&state)[0], "日x");
+        assert_eq!(state.buffer.cursor_pos.colmun, 0);
+    }
+
+    #[test]
+    fn clicks_map_through_gutter_scroll_and_wrap() {
+        let mut state = state_with(
+            &["aaaa bbbb cccc dddd eeee ffff", "xy", "hello"],
+            language::plain_text(),
+        );
+        state.resized((26, 24));
+        let at = |state: &State| {
+            (
+                state.buffer.cursor_pos.line_number,
+                state.buffer.cursor_pos.colmun,
+            )
+        };
+
+        state.dispatch(Command::Click { column: 9, row: 2 });
+        assert_eq!(at(&state), (1, 2));
+        state.dispatch(Command::Click { column: 1, row: 3 });
+        assert_eq!(at(&state), (2, 0));
+        state.dispatch(Command::Click { column: 9, row: 20 });
+        assert_eq!(at(&state), (2, 2));
+
+        run_command(&mut state, "set wrap");
+        state.dispatch(Command::Click { column: 9, row: 2 });
+        assert_eq!(at(&state), (0, 22));
+        state.dispatch(Command::Click { column: 9, row: 3 });
+        assert_eq!(at(&state), (1, 2));
+
+        run_command(&mut state, "set nowrap");
+        state.move_cursor((-1, 28));
+        let left = state.left_col();
+        assert!(left > 0);
+        state.dispatch(Command::Click { column: 7, row: 1 });
+        assert_eq!(at(&state), (0, left));
+    }
+
+    #[test]
+    fn dragging_selects_lines_for_line_ops() {
+        let mut state = state_with(&["a", "b", "c", "d"], language::plain_text());
+        state.dispatch(Command::Click { column: 7, row: 2 });
+        assert!(state.selection().is_none());
+        state.dispatch(Command::Drag { column: 7, row: 3 });
+        let (start, end) = state.selection().unwrap();
+        assert_eq!((start.line_number, end.line_number), (1, 2));
+
+        state.dispatch(Command::LineOp(LineOp::Delete));
+        assert_eq!(lines_of(&state), vec!["a", "d"]);
+        assert!(state.selection().is_none());
+
+        let mut pending = Vec::new();
+        let wheel = Event::Mouse(MouseEvent::Press(MouseButton::WheelDown, 1, 1));
+        assert!(matches!(
+            input_map(&Mode::Insert, &mut pending, wheel),
+            Some(Command::Wheel { lines: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn wheel_scrolls_and_clicks_switch_tabs() {
+        let lines: Vec<String> = (0..100).map(|n| n.to_string()).collect();
+        let lines: Vec<&str> = lines.iter().map(|l| l.as_str()).collect();
+        let mut state = state_with(&lines, language::plain_text());
+        state.resized((80, 12));
+
+        state.dispatch(Command::Wheel {
+            column: 10,
+            row: 3,
+            lines: 3,
+        });
+        assert_eq!(state.top_line(), 3);
+
+        run_command(&mut state, "tabnew");
+        assert_eq!(state.current_tab, 1);
+        state.dispatch(Command::Click { column: 2, row: 1 });
+        assert_eq!(state.current_tab, 0);
+        assert_eq!(state.top_line(), 3);
+    }
+
+    #[test]
+    fn paste_inserts_text_as_it_is() {
+        let mut state = state_with(&["    ab"], language::for_path(Path::new("a.rs")));
+        state.dispatch(Command::ShiftMode(Mode::Insert));
+        state.buffer.cursor_pos.colmun = 5;
+
+        let mut events = b"fn f() {\r\n    (x)\r\n}\x1b[201~"
+            .events_and_raw()
+            .map(|e| e.unwrap());
+        state.paste(&userinput::read_paste(&mut events));
+        assert_eq!(lines_of(&state), vec!["    afn f() {", "    (x)", "}b"]);
+        assert_eq!(state.buffer.cursor_pos.line_number, 2);
+        assert_eq!(state.buffer.cursor_pos.colmun, 1);
+
+        state.dispatch(Command::ShiftMode(Mode::Command));
+        state.paste("set wrap\nignored");
+        assert_eq!(state.command_line, "set wrap");
+    }
+
+    #[test]
+    fn colorscheme_loads_themes_by_name() {
+        let mut state = state_with(&[""], language::plain_text());
+        run_command(&mut state, "colorscheme InspiredGitHub");
+        assert_eq!(state.colorscheme, "InspiredGitHub");
+
+        run_command(&mut state, "colo nonesuch");
+        assert!(state
+            .status_text
+            .starts_with("Cannot find color scheme nonesuch"));
+        run_command(&mut state, "colo");
+        assert_eq!(state.status_text, "InspiredGitHub");
+    }
+}
@@ -1,6 +1,14 @@
+use crate::colour;
+use crate::columns;
+use crate::gutter;
 use crate::highlight::HighlightState;
-use crate::state::{state_update_topic, Mode, StateSnapshot};
+use crate::layout::{self, Rect, WindowId};
+use crate::options::Options;
+use crate::signs::{self, SignColumn, SignKind};
+use crate::state::{state_update_topic, CursorPos, Mode, StateSnapshot, TabView, WindowView};
+use crate::theme::{self, UiColours};
 use crate::userinput::Event;
+use crate::wrap;
 use crate::{
     highlight::HighlightRev,
     pubsub,
@@ -10,48 +18,131 @@ use crate::{
 use bouncer::Bouncer;
 use crossbeam::channel::{after, never};
 use crossbeam::select;
+use lazy_static::lazy_static;
+use signal_hook::{
+    consts::{SIGCONT, SIGTSTP, SIGWINCH},
+    iterator::Signals,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::thread;
 use std::{
     io::{stdin, stdout, Stdin, Stdout, Write},
     time::Duration,
 };
+use syntect::highlighting::Color;
 use termion::{
     clear, color, cursor,
-    input::{Events, TermRead},
+    input::{EventsAndRaw, MouseTerminal, TermReadEventsAndRaw},
     raw::{IntoRawMode, RawTerminal},
+    screen::{self, AlternateScreen, IntoAlternateScreen},
+    style,
 };
+use unicode_width::UnicodeWidthStr;
 
 const FRAME_BUDGET: Duration = Duration::from_millis(16);
 
+/// The terminal's `(width, height)`, sent when it's first known and whenever
+/// it changes.
+pub fn size_topic() -> pubsub::TopicId<(u16, u16)> {
+    pubsub::typed_topic("screen-size")
+}
+
+/// Sent when the terminal window has been resized, so the whole screen needs
+/// redrawing.
+pub fn resize_event_topic() -> pubsub::TopicId<()> {
+    pubsub::typed_topic("terminal-resized")
+}
+
+/// Turn reporting of mouse clicks, drags and wheel turns on and off
+const MOUSE_ON: &str = "\x1b[?1000h\x1b[?1002h\x1b[?1015h\x1b[?1006h";
+const MOUSE_OFF: &str = "\x1b[?1006l\x1b[?1015l\x1b[?1002l\x1b[?1000l";
+
+/// Turn bracketed paste mode, where pasted text is marked out so it can be
+/// told apart from typing, on and off
+const PASTE_ON: &str = "\x1b[?2004h";
+const PASTE_OFF: &str = "\x1b[?2004l";
+
+lazy_static! {
+    /// The terminal's settings from before it was put into raw mode
+    static ref ORIGINAL_MODE: Mutex<Option<libc::termios>> = Mutex::new(None);
+}
+
+fn save_terminal_mode() {
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    // safe: tcgetattr only writes to the termios it's given
+    if unsafe { libc::tcgetattr(libc::STDOUT_FILENO, termios.as_mut_ptr()) } == 0 {
+        // safe: tcgetattr succeeded, so it has filled it in
+        let termios = unsafe { termios.assume_init() };
+        *ORIGINAL_MODE.lock().unwrap_or_else(|e| e.into_inner()) = Some(termios);
+    }
+}
+
+/// Puts the terminal back how the editor found it: cooked mode, no mouse
+/// reporting or bracketed paste, a visible cursor and the main screen. This
+/// can be called from any thread, e.g. by a panic hook, while the display
+/// still holds the terminal.
+pub fn restore() {
+    let original = *ORIGINAL_MODE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(termios) = original {
+        // safe: termios came from tcgetattr on the same terminal
+        unsafe { libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, &termios) };
+    }
+    let mut out = stdout();
+    let _ = write!(
+        out,
+        "{}{}{}{}",
+        MOUSE_OFF,
+        PASTE_OFF,
+        cursor::Show,
+        screen::ToMainScreen
+    );
+    let _ = out.flush();
+}
+
+/// Asks the display to give the terminal back to the shell and stop the
+/// process, as for Ctrl-Z.
+pub fn suspend_topic() -> pubsub::TopicId<()> {
+    pubsub::typed_topic("suspend")
+}
+
+/// Sent when the process is continued after being stopped.
+fn continued_topic() -> pubsub::TopicId<()> {
+    pubsub::typed_topic("continued")
+}
+
 fn terminal_display() -> (TerminalDisplay, TerminalInput) {
     assert!(
         termion::is_tty(&0) && termion::is_tty(&1),
         "Not in a terminal"
     );
-    let mut stdout = stdout()
-        .into_raw_mode()
-        .expect("Unable to set terminal to raw mode... is this a tty?");
+    save_terminal_mode();
+    let mut stdout = MouseTerminal::from(
+        stdout()
+            .into_alternate_screen()
+            .expect("Unable to switch to the alternate screen")
+            .into_raw_mode()
+            .expect("Unable to set terminal to raw mode... is this a tty?"),
+    );
 
     log::debug!("Terminal entered raw mode");
     let stdin = stdin();
+    write!(stdout, "{}", PASTE_ON).unwrap();
     stdout.flush().unwrap();
 
-    let last_displayed = Vec::with_capacity(termion::terminal_size().unwrap().1 as usize + 1);
-
     (
         TerminalDisplay {
-            top_line: 0,
             stdout,
-            last_displayed,
+            last_displayed: HashMap::new(),
         },
         TerminalInput {
-            events: stdin.events(),
+            events: stdin.events_and_raw(),
         },
     )
 }
 
 pub struct TerminalInput {
-    events: Events<Stdin>,
+    events: EventsAndRaw<Stdin>,
 }
 
 pub fn spawn_interface(hub: pubsub::Hub) -> thread::JoinHandle<()> {
@@ -63,8 +154,14 @@ pub fn spawn_interface(hub: pubsub::Hub) -> thread::JoinHandle<()> {
     let input_thread = thread::Builder::new()
         .name("input".into())
         .spawn(move || {
-            for e in input {
-                let send_result = input_hub.send(userinput::topic(), e);
+            let mut input = input;
+            while let Some((e, _)) = input.next() {
+                let send_result = if userinput::is_paste_start(&e) {
+                    let text = userinput::read_paste(&mut input);
+                    input_hub.send(userinput::paste_topic(), text)
+                } else {
+                    input_hub.send(userinput::topic(), e)
+                };
                 if send_result.is_err() {
                     log::debug!("Shutting down listen thread");
                     // nobody is listening
@@ -77,6 +174,25 @@ pub fn spawn_interface(hub: pubsub::Hub) -> thread::JoinHandle<()> {
     // daemonize - let it unwind when the process finishes
     drop(input_thread);
 
+    let mut signal_hub = hub.clone();
+    let mut signals = Signals::new([SIGWINCH, SIGCONT]).expect("Unable to listen for signals");
+    let signal_thread = thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || {
+            for signal in signals.forever() {
+                let sent = if signal == SIGCONT {
+                    signal_hub.send(continued_topic(), ())
+                } else {
+                    signal_hub.send(resize_event_topic(), ())
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+        })
+        .expect("Failed spawning signal listener thread");
+    drop(signal_thread);
+
     thread::Builder::new()
         .name("display".into())
         .spawn(move || {
@@ -85,12 +201,19 @@ pub fn spawn_interface(hub: pubsub::Hub) -> thread::JoinHandle<()> {
             let highlight_receiver =
                 display_hub.get_receiver(crate::highlight::HighlightState::topic());
             let shutdown_receiver = display_hub.get_receiver(crate::editor::shutdown_event_topic());
+            let sign_receiver = display_hub.get_receiver(signs::topic());
+            let resize_receiver = display_hub.get_receiver(resize_event_topic());
+            let suspend_receiver = display_hub.get_receiver(suspend_topic());
+            let continued_receiver = display_hub.get_receiver(continued_topic());
+            let theme_receiver = display_hub.get_receiver(theme::topic());
 
             log::debug!("Initializing display thread");
 
             let mut last_state = StateForDisplay {
                 editor_state: None,
                 highlighter_state: None,
+                signs: SignColumn::default(),
+                ui: UiColours::from_theme(&theme::load(theme::DEFAULT).unwrap_or_default()),
             };
 
             let mut render_start_deadline = Bouncer::builder()
@@ -98,10 +221,16 @@ pub fn spawn_interface(hub: pubsub::Hub) -> thread::JoinHandle<()> {
                 .skip_hot_deadline(Duration::from_millis(2))
                 .build();
 
+            let mut last_size = None;
+
             loop {
                 if render_start_deadline.expired() {
                     log::debug!("Render start deadline hit - updating display");
-                    display.update(&last_state);
+                    let size = display.update(&last_state);
+                    if last_size != Some(size) {
+                        last_size = Some(size);
+                        let _ = display_hub.send(size_topic(), size);
+                    }
                     render_start_deadline.clear();
                 }
 
@@ -133,7 +262,38 @@ pub fn spawn_interface(hub: pubsub::Hub) -> thread::JoinHandle<()> {
                             },
                         };
                     },
-                    recv(time_until_deadline.map(|d| after(d)).unwrap_or(never())) -> _timeout => {}
+                    recv(resize_receiver) -> msg => {
+                        if msg.is_ok() {
+                            log::debug!("Terminal resized - redrawing everything");
+                            display.invalidate();
+                            render_start_deadline.mark();
+                        }
+                    },
+                    recv(suspend_receiver) -> msg => {
+                        if msg.is_ok() {
+                            display.suspend();
+                        }
+                    },
+                    recv(continued_receiver) -> msg => {
+                        if msg.is_ok() {
+                            display.resume();
+                            render_start_deadline.mark();
+                        }
+                    },
+                    recv(theme_receiver) -> msg => {
+                        if let Ok(theme) = msg {
+                            last_state.ui = UiColours::from_theme(&theme);
+                            display.invalidate();
+                            render_start_deadline.mark();
+                        }
+                    },
+                    recv(sign_receiver) -> msg => {
+                        if let Ok(update) = msg {
+                            last_state.signs.update(update);
+                            render_start_deadline.mark();
+                        }
+                    },
+                    recv(time_until_deadline.map(after).unwrap_or(never())) -> _timeout => {}
                 }
             }
         })
@@ -143,34 +303,100 @@ pub fn spawn_interface(hub: pubsub::Hub) -> thread::JoinHandle<()> {
 struct StateForDisplay {
     editor_state: Option<StateSnapshot>,
     highlighter_state: Option<HighlightState>,
+    signs: SignColumn,
+    /// Colours from the theme for everything but the text
+    ui: UiColours,
+}
+
+fn fg(c: Option<Color>) -> String {
+    match c {
+        Some(c) => colour::fg(c, colour::depth()),
+        None => color::Fg(color::Reset).to_string(),
+    }
+}
+
+fn bg(c: Option<Color>) -> String {
+    match c {
+        Some(c) => colour::bg(c, colour::depth()),
+        None => color::Bg(color::Reset).to_string(),
+    }
+}
+
+/// How window titles are drawn: in the theme's status colours, or in
+/// reverse video if it hasn't any.
+fn title_style(ui: &UiColours) -> String {
+    match ui.status_bg {
+        Some(_) => fg(ui.status_fg) + &bg(ui.status_bg),
+        None => style::Invert.to_string(),
+    }
+}
+
+/// The display columns of line `line_number` (whose text is `line`) covered
+/// by a selection from `start` to `end`. Both ends are included, as is the
+/// line break of lines the selection carries on past.
+fn selected_columns(
+    line: &str,
+    line_number: usize,
+    start: &CursorPos,
+    end: &CursorPos,
+    tabstop: usize,
+) -> Option<(usize, usize)> {
+    if line_number < start.line_number || line_number > end.line_number {
+        return None;
+    }
+    let clusters = columns::clusters(line, tabstop);
+    let line_end = clusters
+        .last()
+        .map(|c| c.display_col + c.width)
+        .unwrap_or(0);
+    let first = if line_number == start.line_number {
+        columns::display_col(line, start.colmun, tabstop)
+    } else {
+        0
+    };
+    let last = if line_number == end.line_number {
+        clusters
+            .iter()
+            .find(|c| c.char_col >= end.colmun)
+            .map(|c| c.display_col + c.width.max(1))
+            .unwrap_or(line_end + 1)
+    } else {
+        line_end + 1
+    };
+    Some((first, last))
+}
+
+fn sign_colour(kind: SignKind) -> String {
+    match kind {
+        SignKind::Error | SignKind::Removed => color::Fg(color::Red).to_string(),
+        SignKind::Warning => color::Fg(color::Yellow).to_string(),
+        SignKind::Added => color::Fg(color::Green).to_string(),
+        SignKind::Modified => color::Fg(color::Blue).to_string(),
+        SignKind::Bookmark => color::Fg(color::Cyan).to_string(),
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 enum LineDisplayRevision {
+    #[default]
     New,
     Previous {
         line_id: LineId,
         line_rev: Option<Rev>,
         hl_rev: Option<HighlightRev>,
-        screen_dims: (u16, u16),
+        area: Rect,
+        tabstop: usize,
+        /// The display column the row's text starts from
+        start_col: usize,
+        wrap: bool,
+        gutter: String,
+        /// The display columns of the line that are selected
+        selected: Option<(usize, usize)>,
+        cursor_line: bool,
     },
 }
 
 impl LineDisplayRevision {
-    fn from(
-        line_id: LineId,
-        line_rev: Rev,
-        hl_rev: Option<HighlightRev>,
-        screen_dims: (u16, u16),
-    ) -> Self {
-        LineDisplayRevision::Previous {
-            line_id,
-            line_rev: Some(line_rev),
-            hl_rev,
-            screen_dims,
-        }
-    }
-
     fn is_new(&self, previous: &LineDisplayRevision) -> bool {
         match (self, previous) {
             (Self::New, _) => true,
@@ -180,13 +406,25 @@ impl LineDisplayRevision {
                     line_id: my_line_id,
                     line_rev: my_line_rev,
                     hl_rev: my_hl_rev,
-                    screen_dims: my_screen_dims,
+                    area: my_area,
+                    tabstop: my_tabstop,
+                    start_col: my_start_col,
+                    wrap: my_wrap,
+                    gutter: my_gutter,
+                    selected: my_selected,
+                    cursor_line: my_cursor_line,
                 },
                 Self::Previous {
                     line_id,
                     line_rev,
                     hl_rev,
-                    screen_dims,
+                    area,
+                    tabstop,
+                    start_col,
+                    wrap,
+                    gutter,
+                    selected,
+                    cursor_line,
                 },
             ) => {
                 my_line_id != line_id
@@ -194,109 +432,104 @@ impl LineDisplayRevision {
                     || my_line_rev != line_rev
                     || hl_rev.is_none()
                     || my_hl_rev != hl_rev
-                    || my_screen_dims != screen_dims
+                    || my_area != area
+                    || my_tabstop != tabstop
+                    || my_start_col != start_col
+                    || my_wrap != wrap
+                    || my_gutter != gutter
+                    || my_selected != selected
+                    || my_cursor_line != cursor_line
             }
         }
     }
 }
 
-impl Default for LineDisplayRevision {
-    fn default() -> Self {
-        LineDisplayRevision::New
-    }
+pub struct TerminalDisplay {
+    stdout: MouseTerminal<RawTerminal<AlternateScreen<Stdout>>>,
+    /// What was last drawn on each row of each window
+    last_displayed: HashMap<(WindowId, u16), LineDisplayRevision>,
 }
 
-pub struct TerminalDisplay {
-    top_line: usize,
-    stdout: RawTerminal<Stdout>,
-    last_displayed: Vec<LineDisplayRevision>,
+impl Drop for TerminalDisplay {
+    fn drop(&mut self) {
+        let _ = write!(self.stdout, "{}", PASTE_OFF);
+    }
 }
 
 impl TerminalDisplay {
-    fn update(&mut self, state: &StateForDisplay) {
-        log::debug!("Render start");
-        let (w, h) = termion::terminal_size().expect("unable to check terminal dimensions");
+    /// Restores the terminal and stops the process. Returns once it's been
+    /// continued; the SIGCONT that continues it takes the terminal back.
+    fn suspend(&mut self) {
+        log::debug!("Suspending");
+        let _ = self.stdout.flush();
+        restore();
+        if let Err(e) = signal_hook::low_level::raise(SIGTSTP) {
+            log::error!("Unable to stop: {}", e);
+        }
+    }
 
-        let lines_at_bottom = 2u16;
-        let text_view_height = h - lines_at_bottom;
+    /// Takes the terminal back after the process has been continued, in case
+    /// the shell has changed its mode.
+    fn resume(&mut self) {
+        log::debug!("Resuming");
+        let _ = self.stdout.activate_raw_mode();
+        let _ = write!(
+            self.stdout,
+            "{}{}{}",
+            screen::ToAlternateScreen,
+            MOUSE_ON,
+            PASTE_ON
+        );
+        self.invalidate();
+    }
 
-        self.last_displayed
-            .resize(h as usize + 1, LineDisplayRevision::default());
+    /// Clears the screen and forgets what was drawn, so the next update
+    /// draws everything again.
+    fn invalidate(&mut self) {
+        self.last_displayed.clear();
+        let _ = self.stdout.write_fmt(format_args!("{}", clear::All));
+    }
+
+    /// Redraws the screen, returning its size.
+    fn update(&mut self, state: &StateForDisplay) -> (u16, u16) {
+        log::debug!("Render start");
+        let (w, h) = termion::terminal_size().expect("unable to check terminal dimensions");
 
         if let Some(editor_state) = &state.editor_state {
             let cursor_pos = editor_state.cursor_pos();
-
-            self.top_line = cursor_pos
-                .line_number
-                .saturating_sub((text_view_height as usize).saturating_sub(1));
-
-            let hlstate = &state.highlighter_state;
-            let text = editor_state.text();
-
-            let mut text_lines = text.iter_line_range(
-                self.top_line,
-                self.top_line.saturating_add(text_view_height as usize),
-            );
-            let mut output_line = 1;
-
-            while output_line <= text_view_height {
-                match text_lines.next() {
-                    Some(line) => {
-                        let txt = line.content_str();
-
-                        let (escaped, hl_rev) = match hlstate.as_ref() {
-                            Some(hls) => match hls.highlighted_line(&line) {
-                                Some(hll) => (hll.highlighted_text(), Some(hll.rev())),
-                                None => (txt, None),
-                            },
-                            None => (txt, None),
-                        };
-
-                        let now_key =
-                            LineDisplayRevision::from(line.id(), line.rev(), hl_rev, (w, h));
-                        let last_time = &self.last_displayed[output_line as usize];
-                        let should_render = now_key.is_new(last_time);
-
-                        if should_render {
-                            self.stdout
-                                .write_fmt(format_args!(
-                                    "{}{}{}{:3}@{:2}/{:2}|{}",
-                                    cursor::Goto(1, output_line),
-                                    color::Fg(color::Reset),
-                                    clear::CurrentLine,
-                                    line.line_number(),
-                                    line.rev(),
-                                    hl_rev.unwrap_or(HighlightRev::default()),
-                                    &escaped
-                                ))
-                                .expect("Unable to write to main text area");
-
-                            self.last_displayed[output_line as usize] = now_key;
-                        } else {
-                            self.stdout
-                                .write_fmt(format_args!(
-                                    "{}{}{}{}",
-                                    cursor::Goto(4, output_line),
-                                    color::Bg(color::Blue),
-                                    "@",
-                                    color::Bg(color::Reset)
-                                ))
-                                .expect("Unable to write to main text area");
-                        }
-                    }
-                    None => {
-                        self.stdout
-                            .write_fmt(format_args!(
-                                "{}{}{}{:2}|~",
-                                color::Fg(color::Reset),
-                                cursor::Goto(1, output_line),
-                                clear::CurrentLine,
-                                self.top_line.saturating_add(output_line as usize - 1)
-                            ))
-                            .expect("Unable to write to main text area");
+            let tabs = editor_state.tabs();
+            let arrangement = editor_state
+                .layout()
+                .arrange(layout::text_area((w, h), tabs.len() > 1));
+            if tabs.len() > 1 {
+                self.draw_tab_bar(tabs, w);
+            }
+            let with_titles = arrangement.windows.len() > 1;
+
+            self.last_displayed
+                .retain(|(id, _), _| arrangement.rect_of(*id).is_some());
+
+            let mut cursor = None;
+            for (id, area) in &arrangement.windows {
+                if let Some(window) = editor_state.window(*id) {
+                    let window_cursor =
+                        self.draw_window(window, *area, with_titles, state, editor_state.options());
+                    if window.focused() {
+                        cursor = Some(window_cursor);
                     }
-                };
-                output_line += 1;
+                }
+            }
+
+            for separator in &arrangement.separators {
+                for row in separator.y..separator.y + separator.height {
+                    self.stdout
+                        .write_fmt(format_args!(
+                            "{}{}│",
+                            cursor::Goto(separator.x, row),
+                            color::Fg(color::Reset)
+                        ))
+                        .expect("Unable to write window separator");
+                }
             }
 
             self.stdout
@@ -311,8 +544,12 @@ impl TerminalDisplay {
 
             if editor_state.mode() == &Mode::Command {
                 let command_text = editor_state.command_line();
-                let command_text_disp =
-                    &command_text[command_text.len().saturating_sub(w as usize)..];
+                let command_width = command_text.width();
+                let command_text_disp = columns::slice_columns(
+                    command_text,
+                    command_width.saturating_sub(w as usize - 1),
+                    command_width,
+                );
                 self.stdout
                     .write_fmt(format_args!(
                         "{}{}:{}",
@@ -323,41 +560,325 @@ impl TerminalDisplay {
                     .unwrap();
             } else {
                 let status_text = editor_state.status_text();
-                let status_text_disp = &status_text[..status_text.len().min(w as usize - 1)];
+                let status_text_disp = columns::slice_columns(status_text, 0, w as usize - 1);
                 self.stdout
                     .write_fmt(format_args!(
-                        "{}{}{}\t{:?}\t(l:{},c:{})",
+                        "{}{}{}{}{}\t{:?}\t(l:{},c:{}){}{}",
                         cursor::Goto(1, h),
+                        fg(state.ui.status_fg),
+                        bg(state.ui.status_bg),
                         clear::CurrentLine,
                         status_text_disp,
                         editor_state.mode(),
                         cursor_pos.line_number,
-                        cursor_pos.colmun
+                        cursor_pos.colmun,
+                        color::Fg(color::Reset),
+                        color::Bg(color::Reset)
                     ))
                     .unwrap();
 
-                let display_cursor_ln = (1 + (cursor_pos.line_number - self.top_line) as u16)
-                    .clamp(1, text_view_height);
-                let display_cursor_col = (1 + cursor_pos.colmun as u16 + 10).clamp(1, w);
-
-                self.stdout
-                    .write_fmt(format_args!(
-                        "{}",
-                        cursor::Goto(display_cursor_col, display_cursor_ln)
-                    ))
-                    .unwrap();
+                if let Some((col, row)) = cursor {
+                    self.stdout
+                        .write_fmt(format_args!("{}", cursor::Goto(col, row)))
+                        .unwrap();
+                }
             }
         }
 
         self.stdout.flush().unwrap();
         log::debug!("Render finish");
+        (w, h)
+    }
+
+    /// Draws the tab bar along the top row, with the current tab picked out.
+    fn draw_tab_bar(&mut self, tabs: &[TabView], width: u16) {
+        let mut bar = String::new();
+        let mut used = 0;
+        for (i, tab) in tabs.iter().enumerate() {
+            let label = tab.label(i);
+            let label_width = label.width().min(width as usize - used);
+            let label = columns::fit_to_width(&label, label_width);
+            used += label_width;
+            if tab.current() {
+                bar.push_str(&format!(
+                    "{}{}{}{}",
+                    style::Reset,
+                    style::Bold,
+                    label,
+                    style::Reset
+                ));
+            } else {
+                bar.push_str(&format!("{}{}", style::Invert, label));
+            }
+        }
+
+        self.stdout
+            .write_fmt(format_args!(
+                "{}{}{}{}{}{}",
+                cursor::Goto(1, 1),
+                color::Fg(color::Reset),
+                bar,
+                style::Invert,
+                " ".repeat(width as usize - used),
+                style::Reset
+            ))
+            .expect("Unable to write tab bar");
+    }
+
+    /// Draws a window's text (and its title row, when there are several
+    /// windows), returning where its cursor is on screen.
+    fn draw_window(
+        &mut self,
+        window: &WindowView,
+        area: Rect,
+        with_title: bool,
+        state: &StateForDisplay,
+        options: &Options,
+    ) -> (u16, u16) {
+        let hlstate = &state.highlighter_state;
+        let ui = &state.ui;
+        let text_view_height = area.height.saturating_sub(with_title as u16).max(1) as usize;
+        let cursor_pos = window.cursor_pos();
+        let text = window.text();
+        let gutter_width = gutter::width(text.line_count(), options).min(area.width as usize);
+        let text_width = (area.width as usize - gutter_width).max(1);
+        let sign_width = gutter::sign_width(options).min(gutter_width);
+        let tabstop = options.tabstop;
+
+        let cursor_line = text
+            .iter_line_range(cursor_pos.line_number, cursor_pos.line_number + 1)
+            .next()
+            .map(|l| l.content_str())
+            .unwrap_or_default();
+        let cursor_col = columns::display_col(&cursor_line, cursor_pos.colmun, tabstop);
+
+        // as for the top line, the editor's left column may be out of date
+        let left_col = if options.wrap {
+            0
+        } else {
+            layout::scroll_to_show(
+                window.left_col(),
+                cursor_col,
+                text_width,
+                options.sidescrolloff,
+            )
+        };
+
+        // The display column each of a line's rows starts from
+        let row_starts = |line: &str| -> Vec<usize> {
+            if options.wrap {
+                wrap::row_starts(line, text_width, tabstop)
+                    .iter()
+                    .map(|start| columns::display_col(line, *start, tabstop))
+                    .collect()
+            } else {
+                vec![left_col]
+            }
+        };
+        let cursor_starts = row_starts(&cursor_line);
+        let cursor_segment = wrap::row_of(&cursor_starts, cursor_col);
+
+        // The editor keeps the cursor in view, but the window may not be the
+        // size it expects if the terminal has just been resized.
+        let mut top_line = window.top_line();
+        if cursor_pos.line_number < top_line {
+            top_line = cursor_pos.line_number;
+        } else if cursor_pos.line_number >= top_line + text_view_height {
+            top_line = cursor_pos.line_number + 1 - text_view_height;
+        }
+        let mut rows_above_cursor: Vec<usize> = text
+            .iter_line_range(top_line, cursor_pos.line_number)
+            .map(|l| row_starts(&l.content_str()).len())
+            .collect();
+        let mut rows_to_cursor = rows_above_cursor.iter().sum::<usize>() + cursor_segment;
+        while rows_to_cursor >= text_view_height && !rows_above_cursor.is_empty() {
+            rows_to_cursor -= rows_above_cursor.remove(0);
+            top_line += 1;
+        }
+
+        let mut text_lines =
+            text.iter_line_range(top_line, top_line.saturating_add(text_view_height));
+        let mut row = 0;
+        while row < text_view_height {
+            let line = match text_lines.next() {
+                Some(line) => line,
+                None => break,
+            };
+            let txt = line.content_str();
+            let starts = row_starts(&txt);
+
+            let (escaped, hl_rev) = match hlstate.as_ref() {
+                Some(hls) => match hls.highlighted_line(&line) {
+                    Some(hll) => (hll.highlighted_text(), Some(hll.rev())),
+                    None => (txt.clone(), None),
+                },
+                None => (txt.clone(), None),
+            };
+            let escaped = columns::expand_tabs(&escaped, tabstop);
+            let selected = window.selection().and_then(|(start, end)| {
+                selected_columns(&txt, line.line_number(), start, end, tabstop)
+            });
+            let cursor_line = options.cursorline
+                && window.focused()
+                && line.line_number() == cursor_pos.line_number;
+            let text_bg = bg(ui.cursor_line.filter(|_| cursor_line));
+            let escaped = match (selected, ui.selection) {
+                (Some((start, end)), Some(colour)) => {
+                    columns::style_columns(&escaped, start, end, &bg(Some(colour)), &text_bg)
+                }
+                (Some((start, end)), None) => columns::style_columns(
+                    &escaped,
+                    start,
+                    end,
+                    style::Invert.as_ref(),
+                    style::NoInvert.as_ref(),
+                ),
+                (None, _) => escaped,
+            };
+
+            for (segment, start) in starts.iter().copied().enumerate() {
+                if row >= text_view_height {
+                    break;
+                }
+                let screen_row = area.y + row as u16;
+                let cache_key = (window.id(), screen_row - area.y);
+                let gutter = if segment == 0 {
+                    let number =
+                        gutter::number(line.line_number(), cursor_pos.line_number, options);
+                    let revs = options
+                        .debug
+                        .then(|| (line.rev(), hl_rev.unwrap_or(HighlightRev::default())));
+                    let sign = match state.signs.sign_for(line.id()) {
+                        Some(sign) if sign_width > 0 => format!(
+                            "{}{}{}",
+                            sign_colour(sign.kind()),
+                            &sign.kind().text()[..sign_width],
+                            fg(ui.gutter_fg)
+                        ),
+                        _ => " ".repeat(sign_width),
+                    };
+                    format!(
+                        "{}{}{}{}{}",
+                        fg(ui.gutter_fg),
+                        bg(ui.gutter_bg),
+                        sign,
+                        gutter::label(gutter_width - sign_width, number, revs),
+                        color::Fg(color::Reset),
+                    )
+                } else {
+                    format!(
+                        "{}{}{}{}{}",
+                        fg(ui.gutter_fg),
+                        bg(ui.gutter_bg),
+                        " ".repeat(sign_width),
+                        gutter::continuation(gutter_width - sign_width),
+                        color::Fg(color::Reset),
+                    )
+                };
+                let now_key = LineDisplayRevision::Previous {
+                    line_id: line.id(),
+                    line_rev: Some(line.rev()),
+                    hl_rev,
+                    area,
+                    tabstop,
+                    start_col: start,
+                    wrap: options.wrap,
+                    gutter: gutter.clone(),
+                    selected,
+                    cursor_line,
+                };
+                let should_render = self
+                    .last_displayed
+                    .get(&cache_key)
+                    .map(|last_time| now_key.is_new(last_time))
+                    .unwrap_or(true);
+
+                if should_render {
+                    let end = starts
+                        .get(segment + 1)
+                        .copied()
+                        .unwrap_or(usize::MAX)
+                        .min(start + text_width);
+                    let segment_text = columns::slice_columns(&escaped, start, end);
+                    self.stdout
+                        .write_fmt(format_args!(
+                            "{}{}{}{}{}",
+                            cursor::Goto(area.x, screen_row),
+                            color::Fg(color::Reset),
+                            columns::fit_to_width(
+                                &format!("{}{}{}", gutter, text_bg, segment_text),
+                                area.width as usize
+                            ),
+                            color::Fg(color::Reset),
+                            color::Bg(color::Reset),
+                        ))
+                        .expect("Unable to write to main text area");
+
+                    self.last_displayed.insert(cache_key, now_key);
+                } else if options.debug && gutter_width > 0 {
+                    // mark rows that were left as they were
+                    self.stdout
+                        .write_fmt(format_args!(
+                            "{}{}{}{}",
+                            cursor::Goto(area.x + gutter_width as u16 - 1, screen_row),
+                            ui.marker
+                                .map(|c| bg(Some(c)))
+                                .unwrap_or_else(|| color::Bg(color::Blue).to_string()),
+                            "@",
+                            color::Bg(color::Reset)
+                        ))
+                        .expect("Unable to write to main text area");
+                }
+                row += 1;
+            }
+        }
+
+        for row in row..text_view_height {
+            let screen_row = area.y + row as u16;
+            self.stdout
+                .write_fmt(format_args!(
+                    "{}{}{}{}",
+                    fg(ui.filler),
+                    cursor::Goto(area.x, screen_row),
+                    columns::fit_to_width("~", area.width as usize),
+                    color::Fg(color::Reset)
+                ))
+                .expect("Unable to write to main text area");
+            self.last_displayed.remove(&(window.id(), row as u16));
+        }
+
+        if with_title {
+            let title = format!(
+                " {}{}",
+                window.buffer_name(),
+                if window.modified() { " [+]" } else { "" }
+            );
+            self.stdout
+                .write_fmt(format_args!(
+                    "{}{}{}{}{}",
+                    cursor::Goto(area.x, area.y + text_view_height as u16),
+                    title_style(ui),
+                    if window.focused() { "*" } else { " " },
+                    columns::fit_to_width(&title, (area.width as usize).saturating_sub(1)),
+                    style::Reset
+                ))
+                .expect("Unable to write window title");
+        }
+
+        let cursor_display_col = cursor_col - cursor_starts[cursor_segment];
+        let col = (area.x + (cursor_display_col + gutter_width) as u16)
+            .clamp(area.x, area.x + area.width.saturating_sub(1));
+        let row =
+            (area.y + rows_to_cursor as u16).clamp(area.y, area.y + text_view_height as u16 - 1);
+        (col, row)
     }
 }
 
+/// Input events, along with the bytes they were parsed from.
 impl Iterator for TerminalInput {
-    type Item = Event;
+    type Item = (Event, Vec<u8>);
 
-    fn next(&mut self) -> Option<Event> {
+    fn next(&mut self) -> Option<(Event, Vec<u8>)> {
         Some(
             self.events
                 .next()
@@ -366,12 +887,3 @@ impl Iterator for TerminalInput {
         )
     }
 }
-
-impl Drop for TerminalDisplay {
-    fn drop(&mut self) {
-        let _ = self
-            .stdout
-            .write_fmt(format_args!("{}{}", cursor::Goto(1, 1), clear::AfterCursor));
-        let _ = self.stdout.flush();
-    }
-}
@@ -1,21 +1,26 @@
-use std::usize;
 use std::{
     any::Any,
     cmp::{self},
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     fmt::Display,
     marker::PhantomData,
+    ops::Range,
 };
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use lazy_static::lazy_static;
 
+use crate::columns;
+use similar::Algorithm;
+use unicode_segmentation::UnicodeSegmentation;
+
 lazy_static! {
     static ref EMPTY_STRING: Arc<String> = Arc::new(String::new());
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Rev {
     rev: u64,
 }
@@ -32,12 +37,6 @@ impl From<u64> for Rev {
     }
 }
 
-impl Default for Rev {
-    fn default() -> Self {
-        Rev { rev: 0 }
-    }
-}
-
 impl Rev {
     fn bump(mut self) -> Self {
         self.rev += 1;
@@ -45,23 +44,14 @@ impl Rev {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct LineId {
     id: u64,
 }
 
-impl Default for LineId {
-    fn default() -> Self {
-        LineId { id: 0 }
-    }
-}
-
-impl LineId {
-    fn bump(mut self) -> Self {
-        self.id += 1;
-        self
-    }
-}
+/// Line ids are unique across all texts, so that highlighting and display
+/// caches keyed on them can't confuse lines from different buffers.
+static NEXT_LINE_ID: AtomicU64 = AtomicU64::new(1);
 
 struct NoSend(PhantomData<dyn Any>);
 
@@ -69,7 +59,6 @@ const NO_SEND: NoSend = NoSend(PhantomData);
 
 pub struct Text {
     rev: Rev,
-    next_line_id: LineId,
     revs_before: BTreeMap<usize, Rev>,
     lines: Vec<Line>,
     _nosend: NoSend,
@@ -88,7 +77,7 @@ pub struct Line {
     content_string: Arc<String>,
 }
 
-impl<'a, S> From<S> for LineContent
+impl<S> From<S> for LineContent
 where
     S: Into<String>,
 {
@@ -102,6 +91,10 @@ where
 }
 
 impl Line {
+    pub fn id(&self) -> LineId {
+        self.id
+    }
+
     pub fn rev(&self) -> Rev {
         self.rev
     }
@@ -136,12 +129,71 @@ impl Line {
         self.on_content_change();
     }
 
+    pub fn set_content(&mut self, content: Vec<char>) {
+        self.content = content;
+        self.on_content_change();
+    }
+
     fn on_content_change(&mut self) {
         let new_content_string = self.content.iter().collect();
         self.content_string = Arc::new(new_content_string);
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    Upper,
+    Lower,
+    Title,
+    /// Swaps upper and lower case, as vim's `g~`
+    Toggle,
+}
+
+impl Case {
+    fn apply(self, s: &str) -> Vec<char> {
+        match self {
+            Case::Upper => s.to_uppercase().chars().collect(),
+            Case::Lower => s.to_lowercase().chars().collect(),
+            Case::Title => {
+                let mut result = Vec::with_capacity(s.len());
+                let mut start_of_word = true;
+                for c in s.chars() {
+                    if start_of_word {
+                        result.extend(c.to_uppercase());
+                    } else {
+                        result.extend(c.to_lowercase());
+                    }
+                    start_of_word = !c.is_alphanumeric() && c != '\'';
+                }
+                result
+            }
+            Case::Toggle => s
+                .chars()
+                .flat_map(|c| {
+                    if c.is_uppercase() {
+                        c.to_lowercase().collect::<Vec<_>>()
+                    } else {
+                        c.to_uppercase().collect()
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The char indices at which `line`'s grapheme clusters start.
+fn grapheme_starts(line: &str) -> impl Iterator<Item = usize> + '_ {
+    line.graphemes(true).scan(0, |col, g| {
+        let start = *col;
+        *col += g.chars().count();
+        Some(start)
+    })
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
 #[derive(Debug, Clone)]
 pub struct LineView {
     max_rev_before: Rev,
@@ -179,14 +231,14 @@ pub struct LineViewIterator {
     end: usize,
 }
 
-impl<'a> Iterator for LineViewIterator {
+impl Iterator for LineViewIterator {
     type Item = LineView;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.idx >= self.end {
             None
         } else {
-            let ret = self.lines.get(self.idx).map(|lv| lv.clone());
+            let ret = self.lines.get(self.idx).cloned();
             self.idx += 1;
             ret
         }
@@ -201,7 +253,15 @@ pub struct TextView {
 }
 
 impl TextView {
-    pub fn iter_lines<'a>(&self) -> impl Iterator<Item = LineView> {
+    pub fn rev(&self) -> Rev {
+        self.rev
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn iter_lines(&self) -> impl Iterator<Item = LineView> {
         self.iter_line_range(0, self.lines.len())
     }
 
@@ -214,11 +274,16 @@ impl TextView {
     }
 }
 
+impl Default for Text {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Text {
     pub fn new() -> Self {
         Text {
             rev: Rev::default(),
-            next_line_id: LineId::default(),
             revs_before: BTreeMap::new(),
             lines: Vec::new(),
             _nosend: NO_SEND,
@@ -226,8 +291,9 @@ impl Text {
     }
 
     fn bump_line_id(&mut self) -> LineId {
-        self.next_line_id = self.next_line_id.bump();
-        self.next_line_id
+        LineId {
+            id: NEXT_LINE_ID.fetch_add(1, Ordering::Relaxed),
+        }
     }
 
     fn bump_rev(&mut self) -> Rev {
@@ -238,7 +304,6 @@ impl Text {
     pub fn from(lines: &[String]) -> Self {
         let mut text = Text {
             rev: Rev::default(),
-            next_line_id: LineId::default(),
             revs_before: BTreeMap::new(),
             lines: Vec::with_capacity(lines.len()),
             _nosend: NO_SEND,
@@ -265,10 +330,9 @@ impl Text {
     pub fn line_mut(&mut self, ln_number: usize) -> Option<&mut Line> {
         let rev = self.bump_rev();
         self.line_changed(ln_number);
-        self.lines.get_mut(ln_number).map(move |mut ln| {
-            ln.rev = rev;
-            ln
-        })
+        let ln = self.lines.get_mut(ln_number)?;
+        ln.rev = rev;
+        Some(ln)
     }
 
     pub fn line_mut_populate(&mut self, ln_number: usize) -> &mut Line {
@@ -306,6 +370,233 @@ impl Text {
         Some(self.lines.remove(ln_number))
     }
 
+    /// Marks a line as changed without touching its content, e.g. after
+    /// it has been moved.
+    fn touch_line(&mut self, ln_number: usize) {
+        let rev = self.bump_rev();
+        self.lines[ln_number].rev = rev;
+        self.line_changed(ln_number);
+    }
+
+    /// Inserts a copy of the given lines after them.
+    pub fn duplicate_lines(&mut self, lines: Range<usize>) {
+        let end = lines.end.min(self.lines.len());
+        for ln in lines.start..end {
+            let content = self.lines[ln].content.clone();
+            self.insert_line_from_chars(end + ln - lines.start, content);
+        }
+    }
+
+    pub fn swap_lines(&mut self, a: usize, b: usize) {
+        if a.max(b) >= self.lines.len() || a == b {
+            return;
+        }
+        self.lines.swap(a, b);
+        self.touch_line(a.max(b));
+        self.touch_line(a.min(b));
+    }
+
+    /// Joins a line with the one after it, separated by a single space.
+    /// Returns the column at which the lines were joined.
+    pub fn join_lines(&mut self, ln_number: usize) -> Option<usize> {
+        if ln_number + 1 >= self.lines.len() {
+            return None;
+        }
+        let next = self.remove_line(ln_number + 1)?;
+        let line = self.line_mut(ln_number)?;
+
+        while line
+            .content
+            .last()
+            .map(|c| c.is_whitespace())
+            .unwrap_or(false)
+        {
+            line.content.pop();
+        }
+        let join_col = line.content.len();
+        let next_content = next.content_string.trim_start();
+        if !line.content.is_empty() && !next_content.is_empty() {
+            line.content.push(' ');
+        }
+        line.content.extend(next_content.chars());
+        line.on_content_change();
+
+        Some(join_col)
+    }
+
+    /// The column of the grapheme cluster after the one at `col`, or the end
+    /// of the line.
+    pub fn next_grapheme(&self, ln_number: usize, col: usize) -> usize {
+        let line = match self.line(ln_number) {
+            Some(line) => line,
+            None => return col,
+        };
+        grapheme_starts(&line.content_string)
+            .find(|start| *start > col)
+            .unwrap_or_else(|| line.char_count().max(col))
+    }
+
+    /// The column of the grapheme cluster before the one at `col`.
+    pub fn prev_grapheme(&self, ln_number: usize, col: usize) -> usize {
+        self.line(ln_number)
+            .and_then(|line| {
+                grapheme_starts(&line.content_string)
+                    .take_while(|start| *start < col)
+                    .last()
+            })
+            .unwrap_or(0)
+    }
+
+    /// `col`, moved back to the start of the grapheme cluster it's part of.
+    pub fn grapheme_start(&self, ln_number: usize, col: usize) -> usize {
+        self.line(ln_number)
+            .filter(|line| col < line.char_count())
+            .and_then(|line| {
+                grapheme_starts(&line.content_string)
+                    .take_while(|start| *start <= col)
+                    .last()
+            })
+            .unwrap_or(col)
+    }
+
+    /// Inserts `s` at `col` of line `ln_number` as it is, starting a new line
+    /// at each newline. Returns the position just after the inserted text.
+    pub fn insert_str(&mut self, ln_number: usize, col: usize, s: &str) -> (usize, usize) {
+        let line = self.line_mut_populate(ln_number);
+        let col = col.min(line.content.len());
+        let rest = line.content.split_off(col);
+        let mut pieces = s.split('\n');
+        line.content
+            .extend(pieces.next().unwrap_or_default().chars());
+        line.on_content_change();
+
+        let mut end = (ln_number, line.content.len());
+        for piece in pieces {
+            end = (end.0 + 1, piece.chars().count());
+            self.insert_line_from_chars(end.0, piece.chars().collect());
+        }
+
+        let line = self.line_mut(end.0).expect("line inserted above");
+        line.content.extend(rest);
+        line.on_content_change();
+        end
+    }
+
+    /// Deletes the word (and any whitespace) before `col`, returning the
+    /// column the deletion started at.
+    pub fn delete_word_before(&mut self, ln_number: usize, col: usize) -> usize {
+        let line = match self.lines.get(ln_number) {
+            Some(l) => l,
+            None => return col,
+        };
+        let col = col.min(line.content.len());
+        let mut start = col;
+        while start > 0 && line.content[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        if start > 0 {
+            let word = is_word_char(line.content[start - 1]);
+            while start > 0
+                && !line.content[start - 1].is_whitespace()
+                && is_word_char(line.content[start - 1]) == word
+            {
+                start -= 1;
+            }
+        }
+
+        if start < col {
+            let line = self.line_mut(ln_number).expect("line checked above");
+            line.content.drain(start..col);
+            line.on_content_change();
+        }
+        start
+    }
+
+    pub fn change_case(&mut self, lines: Range<usize>, case: Case) {
+        for ln in lines.start..lines.end.min(self.lines.len()) {
+            let changed = case.apply(&self.lines[ln].content_string);
+            if changed != self.lines[ln].content {
+                if let Some(l) = self.line_mut(ln) {
+                    l.set_content(changed);
+                }
+            }
+        }
+    }
+
+    pub fn sort_lines(&mut self, lines: Range<usize>) {
+        self.reorder_lines(lines, |ls| ls.sort_by(|a, b| a.content.cmp(&b.content)));
+    }
+
+    pub fn reverse_lines(&mut self, lines: Range<usize>) {
+        self.reorder_lines(lines, |ls| ls.reverse());
+    }
+
+    /// Removes lines that repeat an earlier line in the range, returning how
+    /// many were removed.
+    pub fn unique_lines(&mut self, lines: Range<usize>) -> usize {
+        let end = lines.end.min(self.lines.len());
+        let mut seen = HashSet::new();
+        let mut removed = 0;
+        let mut ln = lines.start;
+        while ln < end - removed {
+            if seen.insert(self.lines[ln].content_string.clone()) {
+                ln += 1;
+            } else {
+                self.remove_line(ln);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn reorder_lines<F>(&mut self, lines: Range<usize>, reorder: F)
+    where
+        F: FnOnce(&mut [Line]),
+    {
+        let end = lines.end.min(self.lines.len());
+        if lines.start >= end {
+            return;
+        }
+        let before: Vec<LineId> = self.lines[lines.start..end].iter().map(|l| l.id).collect();
+        reorder(&mut self.lines[lines.start..end]);
+        for ln in (lines.start..end).rev() {
+            if self.lines[ln].id != before[ln - lines.start] {
+                self.touch_line(ln);
+            }
+        }
+    }
+
+    /// Changes the text to `new_lines`, only touching lines that differ so
+    /// that unchanged lines keep their ids and revisions.
+    pub fn apply_lines(&mut self, new_lines: &[String]) {
+        let old_lines: Vec<String> = self
+            .lines
+            .iter()
+            .map(|l| l.content_string().to_string())
+            .collect();
+        let ops = similar::capture_diff_slices(Algorithm::Myers, &old_lines, new_lines);
+
+        // Back to front, so earlier line numbers stay valid.
+        for op in ops.iter().rev() {
+            let (_, old, new) = op.as_tag_tuple();
+            let shared = old.len().min(new.len());
+            for i in 0..shared {
+                let (ln, content) = (old.start + i, &new_lines[new.start + i]);
+                if old_lines[ln] != *content {
+                    if let Some(l) = self.line_mut(ln) {
+                        l.set_content(content.chars().collect());
+                    }
+                }
+            }
+            for _ in shared..old.len() {
+                self.remove_line(old.start + shared);
+            }
+            for i in shared..new.len() {
+                self.insert_line(old.start + i, new_lines[new.start + i].as_str());
+            }
+        }
+    }
+
     pub fn insert_line<S>(&mut self, ln_number: usize, s: S)
     where
         S: Into<LineContent>,
@@ -344,6 +635,68 @@ impl Text {
         self.lines.len()
     }
 
+    pub fn rev(&self) -> Rev {
+        self.rev
+    }
+
+    /// Removes trailing whitespace from lines changed after `since`,
+    /// returning how many lines were changed.
+    pub fn strip_trailing_whitespace(&mut self, since: Rev) -> usize {
+        let mut stripped = 0;
+        for ln in 0..self.lines.len() {
+            let line = &self.lines[ln];
+            let len = line.content.len();
+            let trimmed = line.content_string.trim_end().chars().count();
+            if line.rev > since && trimmed < len {
+                if let Some(l) = self.line_mut(ln) {
+                    l.content.truncate(trimmed);
+                    l.on_content_change();
+                }
+                stripped += 1;
+            }
+        }
+        stripped
+    }
+
+    /// Removes empty lines from the end of the text, so that it is written
+    /// with a single final newline.
+    pub fn trim_trailing_blank_lines(&mut self) -> usize {
+        let mut removed = 0;
+        while self.lines.len() > 1 && self.lines[self.lines.len() - 1].content.is_empty() {
+            self.remove_line(self.lines.len() - 1);
+            removed += 1;
+        }
+        removed
+    }
+
+    /// Rewrites the leading whitespace of every line with `indent_string`,
+    /// which is given the indentation's width in display columns.
+    pub fn convert_indentation(
+        &mut self,
+        tabstop: usize,
+        indent_string: &dyn Fn(usize) -> String,
+    ) -> usize {
+        let mut converted = 0;
+        for ln in 0..self.lines.len() {
+            let line = &self.lines[ln];
+            let indent_len = line
+                .content
+                .iter()
+                .take_while(|c| c.is_whitespace())
+                .count();
+            let width = columns::display_col(&line.content_string, indent_len, tabstop);
+            let indent: Vec<char> = indent_string(width).chars().collect();
+            if line.content[..indent_len] != indent[..] {
+                if let Some(l) = self.line_mut(ln) {
+                    l.content.splice(..indent_len, indent);
+                    l.on_content_change();
+                }
+                converted += 1;
+            }
+        }
+        converted
+    }
+
     fn line_changed(&mut self, ln_number: usize) {
         self.revs_before.insert(ln_number, self.rev);
         let _ = self.revs_before.split_off(&(ln_number + 1));
@@ -505,4 +858,128 @@ mod test {
         assert_eq!(line_iter.next().unwrap().max_rev_before(), Rev::from(5));
         assert!(line_iter.next().is_none());
     }
+
+    fn text_of(lines: &[&str]) -> Text {
+        let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        Text::from(&lines)
+    }
+
+    fn contents(t: &Text) -> Vec<String> {
+        t.iter_lines()
+            .map(|l| l.content_str().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn duplicate_swap_and_join_lines() {
+        let mut t = text_of(&["a", "  b  ", "   c"]);
+        t.duplicate_lines(0..1);
+        assert_eq!(contents(&t), vec!["a", "a", "  b  ", "   c"]);
+
+        let id = t.line(2).unwrap().id();
+        t.swap_lines(1, 2);
+        assert_eq!(contents(&t), vec!["a", "  b  ", "a", "   c"]);
+        assert_eq!(t.line(1).unwrap().id(), id);
+
+        assert_eq!(t.join_lines(1), Some(3));
+        assert_eq!(contents(&t), vec!["a", "  b a", "   c"]);
+        assert_eq!(t.join_lines(2), None);
+
+        t.duplicate_lines(1..3);
+        assert_eq!(contents(&t), vec!["a", "  b a", "   c", "  b a", "   c"]);
+    }
+
+    #[test]
+    fn moves_over_whole_grapheme_clusters() {
+        let t = text_of(&["ae\u{301}👍🏽日"]);
+        assert_eq!(t.next_grapheme(0, 0), 1);
+        assert_eq!(t.next_grapheme(0, 1), 3);
+        assert_eq!(t.next_grapheme(0, 3), 5);
+        assert_eq!(t.next_grapheme(0, 5), 6);
+        assert_eq!(t.next_grapheme(0, 6), 6);
+        assert_eq!(t.prev_grapheme(0, 5), 3);
+        assert_eq!(t.prev_grapheme(0, 3), 1);
+        assert_eq!(t.prev_grapheme(0, 0), 0);
+        assert_eq!(t.grapheme_start(0, 2), 1);
+        assert_eq!(t.grapheme_start(0, 4), 3);
+        assert_eq!(t.grapheme_start(0, 6), 6);
+    }
+
+    #[test]
+    fn delete_word_before_stops_at_word_boundaries() {
+        let mut t = text_of(&["foo.bar_baz  qux"]);
+        assert_eq!(t.delete_word_before(0, 16), 13);
+        assert_eq!(contents(&t), vec!["foo.bar_baz  "]);
+        assert_eq!(t.delete_word_before(0, 13), 4);
+        assert_eq!(contents(&t), vec!["foo."]);
+        assert_eq!(t.delete_word_before(0, 4), 3);
+        assert_eq!(t.delete_word_before(0, 3), 0);
+        assert_eq!(contents(&t), vec![""]);
+    }
+
+    #[test]
+    fn change_case_over_range() {
+        let mut t = text_of(&["hello world", "it's FINE", "x"]);
+        t.change_case(0..2, Case::Title);
+        assert_eq!(contents(&t), vec!["Hello World", "It's Fine", "x"]);
+        t.change_case(1..3, Case::Upper);
+        assert_eq!(contents(&t), vec!["Hello World", "IT'S FINE", "X"]);
+        t.change_case(0..1, Case::Lower);
+        assert_eq!(contents(&t)[0], "hello world");
+        t.change_case(1..2, Case::Toggle);
+        assert_eq!(contents(&t)[1], "it's fine");
+        t.change_case(0..1, Case::Toggle);
+        assert_eq!(contents(&t)[0], "HELLO WORLD");
+    }
+
+    #[test]
+    fn sort_reverse_and_unique_lines() {
+        let mut t = text_of(&["z", "c", "a", "c", "b", "a"]);
+        t.sort_lines(1..5);
+        assert_eq!(contents(&t), vec!["z", "a", "b", "c", "c", "a"]);
+        t.reverse_lines(0..6);
+        assert_eq!(contents(&t), vec!["a", "c", "c", "b", "a", "z"]);
+        assert_eq!(t.unique_lines(0..6), 2);
+        assert_eq!(contents(&t), vec!["a", "c", "b", "z"]);
+    }
+
+    #[test]
+    fn apply_lines_keeps_unchanged_line_ids() {
+        let mut t = text_of(&["a", "b", "c", "d"]);
+        let ids: Vec<LineId> = (0..4).map(|ln| t.line(ln).unwrap().id()).collect();
+
+        let new_lines: Vec<String> = ["a", "B", "x", "c"].iter().map(|s| s.to_string()).collect();
+        t.apply_lines(&new_lines);
+
+        assert_eq!(contents(&t), vec!["a", "B", "x", "c"]);
+        assert_eq!(t.line(0).unwrap().id(), ids[0]);
+        assert_eq!(t.line(3).unwrap().id(), ids[2]);
+    }
+
+    #[test]
+    fn save_cleanups() {
+        let mut t = text_of(&["a  ", "\tb ", "", ""]);
+        let loaded = t.rev();
+        t.line_mut(1).unwrap().insert(0, ' ');
+
+        assert_eq!(t.strip_trailing_whitespace(loaded), 1);
+        assert_eq!(t.trim_trailing_blank_lines(), 2);
+        assert_eq!(contents(&t), vec!["a  ", " \tb"]);
+
+        assert_eq!(t.convert_indentation(4, &|w| " ".repeat(w)), 1);
+        assert_eq!(contents(&t), vec!["a  ", "    b"]);
+    }
+
+    #[test]
+    fn inserts_text_across_lines() {
+        let mut t = text_of(&["ab", "c"]);
+        assert_eq!(t.insert_str(0, 1, "x"), (0, 2));
+        assert_eq!(contents(&t), vec!["axb", "c"]);
+
+        assert_eq!(t.insert_str(0, 2, "1\n  2\n3"), (2, 1));
+        assert_eq!(contents(&t), vec!["ax1", "  2", "3b", "c"]);
+
+        assert_eq!(t.insert_str(3, 1, "\n"), (4, 0));
+        assert_eq!(contents(&t), vec!["ax1", "  2", "3b", "c", ""]);
+    }
 }
@@ -0,0 +1,173 @@
+use crate::config;
+use crate::pubsub;
+use lazy_static::lazy_static;
+use std::path::Path;
+use std::sync::Arc;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+
+/// The colour scheme used until another is chosen with `:colorscheme`
+pub const DEFAULT: &str = "base16-ocean.dark";
+
+lazy_static! {
+    static ref BUILT_IN: ThemeSet = ThemeSet::load_defaults();
+}
+
+/// Sent when a new colour scheme has been chosen.
+pub fn topic() -> pubsub::TopicId<Arc<Theme>> {
+    pubsub::typed_topic("theme")
+}
+
+/// Loads the colour scheme called `name`: `<config dir>/themes/<name>.tmTheme`
+/// if there is one, or else one of syntect's built-in themes.
+pub fn load(name: &str) -> Result<Theme, String> {
+    load_from(
+        config::config_dir().map(|d| d.join("themes")).as_deref(),
+        name,
+    )
+}
+
+fn load_from(themes_dir: Option<&Path>, name: &str) -> Result<Theme, String> {
+    if let Some(dir) = themes_dir {
+        let path = dir.join(format!("{}.tmTheme", name));
+        if path.exists() {
+            return ThemeSet::get_theme(&path).map_err(|e| format!("{}: {}", path.display(), e));
+        }
+    }
+    BUILT_IN.themes.get(name).cloned().ok_or_else(|| {
+        format!(
+            "Cannot find color scheme {} (have {})",
+            name,
+            names(themes_dir).join(", ")
+        )
+    })
+}
+
+/// The names of the built-in colour schemes and those in `themes_dir`.
+fn names(themes_dir: Option<&Path>) -> Vec<String> {
+    let mut names: Vec<String> = BUILT_IN.themes.keys().cloned().collect();
+    if let Some(Ok(paths)) = themes_dir.map(ThemeSet::discover_theme_paths) {
+        names.extend(
+            paths
+                .iter()
+                .filter_map(|p| p.file_stem())
+                .map(|s| s.to_string_lossy().into_owned()),
+        );
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Colours for the parts of the screen other than the text, from a theme's
+/// settings. Anything the theme leaves out is drawn in the terminal's own
+/// colours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UiColours {
+    pub status_fg: Option<Color>,
+    pub status_bg: Option<Color>,
+    pub gutter_fg: Option<Color>,
+    pub gutter_bg: Option<Color>,
+    pub selection: Option<Color>,
+    pub cursor_line: Option<Color>,
+    /// The `~` on rows past the end of the text
+    pub filler: Option<Color>,
+    /// The mark debug mode puts on rows that weren't redrawn
+    pub marker: Option<Color>,
+}
+
+impl UiColours {
+    pub fn from_theme(theme: &Theme) -> Self {
+        let s = &theme.settings;
+        // terminals can't blend, so translucent colours are blended with
+        // the theme's background here
+        let solid = |c: Option<Color>| c.map(|c| blend(c, s.background));
+        UiColours {
+            status_fg: solid(s.foreground),
+            status_bg: solid(s.line_highlight.or(s.selection)),
+            gutter_fg: solid(s.gutter_foreground.or(s.guide)),
+            gutter_bg: solid(s.gutter),
+            selection: solid(s.selection),
+            cursor_line: solid(s.line_highlight),
+            filler: solid(s.guide.or(s.gutter_foreground)),
+            marker: solid(s.accent.or(s.find_highlight)),
+        }
+    }
+}
+
+/// `colour` drawn over `background` with its alpha, made opaque.
+fn blend(colour: Color, background: Option<Color>) -> Color {
+    let background = match background {
+        Some(b) => b,
+        None => return Color { a: 0xff, ..colour },
+    };
+    let a = colour.a as u32;
+    let mix = |c: u8, b: u8| ((c as u32 * a + b as u32 * (0xff - a)) / 0xff) as u8;
+    Color {
+        r: mix(colour.r, background.r),
+        g: mix(colour.g, background.g),
+        b: mix(colour.b, background.b),
+        a: 0xff,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    const THEME: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>name</key>
+    <string>Test</string>
+    <key>settings</key>
+    <array>
+        <dict>
+            <key>settings</key>
+            <dict>
+                <key>foreground</key>
+                <string>#102030</string>
+                <key>background</key>
+                <string>#000000</string>
+                <key>lineHighlight</key>
+                <string>#FFFFFF80</string>
+                <key>selection</key>
+                <string>#445566</string>
+            </dict>
+        </dict>
+    </array>
+</dict>
+</plist>
+"#;
+
+    #[test]
+    fn loads_built_in_and_user_themes() {
+        let dir = std::env::temp_dir().join(format!("jete-themes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.tmTheme"), THEME).unwrap();
+
+        assert!(load_from(Some(&dir), DEFAULT).is_ok());
+        assert!(names(Some(&dir)).contains(&"test".to_string()));
+        let err = load_from(Some(&dir), "nonesuch").unwrap_err();
+        assert!(err.contains("InspiredGitHub"));
+
+        let theme = load_from(Some(&dir), "test").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let ui = UiColours::from_theme(&theme);
+        let grey = Color {
+            r: 0x80,
+            g: 0x80,
+            b: 0x80,
+            a: 0xff,
+        };
+        assert_eq!(ui.cursor_line, Some(grey));
+        assert_eq!(ui.status_bg, Some(grey));
+        assert_eq!(
+            ui.selection.map(|c| (c.r, c.g, c.b)),
+            Some((0x44, 0x55, 0x66))
+        );
+        assert_eq!(ui.gutter_bg, None);
+    }
+}
@@ -1,5 +1,5 @@
 pub use crate::pubsub::{typed_topic, TopicId};
-pub use termion::event::{Event, Key, MouseEvent};
+pub use termion::event::{Event, Key, MouseButton, MouseEvent};
 pub trait UserInputSource: Send + 'static {
     fn events(&mut self) -> &mut dyn Iterator<Item = Event>;
 }
@@ -7,3 +7,47 @@ pub trait UserInputSource: Send + 'static {
 pub fn topic() -> TopicId<Event> {
     typed_topic("input")
 }
+
+/// Text pasted into the terminal, sent all at once rather than as keys.
+pub fn paste_topic() -> TopicId<String> {
+    typed_topic("paste")
+}
+
+/// What the terminal sends around pasted text in bracketed paste mode
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+pub fn is_paste_start(e: &Event) -> bool {
+    matches!(e, Event::Unsupported(bytes) if bytes == PASTE_START)
+}
+
+/// Reads the text of a paste whose start has just been seen, up to and
+/// including the end of the paste. The text comes from the bytes that were
+/// sent rather than the events they parse as, which lose the difference
+/// between `\r` and `\n` and turn tabs and other control characters into keys.
+pub fn read_paste(events: &mut impl Iterator<Item = (Event, Vec<u8>)>) -> String {
+    let mut bytes = Vec::new();
+    for (e, raw) in events {
+        match e {
+            Event::Unsupported(end) if end == PASTE_END => break,
+            _ => bytes.extend(raw),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termion::input::TermReadEventsAndRaw;
+
+    #[test]
+    fn reads_a_paste_up_to_its_end() {
+        let mut events = b"\x1b[200~a\r\n\tb\n\x7f\xc3\xa9\x1b[201~c"
+            .events_and_raw()
+            .map(|e| e.unwrap());
+        assert!(is_paste_start(&events.next().unwrap().0));
+        assert_eq!(read_paste(&mut events), "a\r\n\tb\n\x7f\u{e9}");
+        assert_eq!(events.next().unwrap().0, Event::Key(Key::Char('c')));
+    }
+}
@@ -0,0 +1,101 @@
+use crate::columns;
+
+/// The char indices at which each display row of `line` starts when it's
+/// soft-wrapped to `width` columns. Rows break after whitespace where they
+/// can, and mid-word only when a word is wider than a whole row. Grapheme
+/// clusters are never split.
+pub fn row_starts(line: &str, width: usize, tabstop: usize) -> Vec<usize> {
+    let width = width.max(1);
+    let clusters = columns::clusters(line, tabstop);
+    let mut starts = vec![0];
+    // index into `clusters` of the current row's first cluster
+    let mut row_start = 0;
+    let mut row_width = 0;
+    let mut last_break = None;
+
+    for (i, cluster) in clusters.iter().enumerate() {
+        if row_width + cluster.width > width && row_width > 0 {
+            let start = match last_break {
+                Some(b) if b > row_start && b <= i => b,
+                _ => i,
+            };
+            starts.push(clusters[start].char_col);
+            row_start = start;
+            row_width = clusters[start..i].iter().map(|c| c.width).sum();
+            last_break = None;
+        }
+        row_width += cluster.width;
+        if cluster.text.chars().all(char::is_whitespace) {
+            last_break = Some(i + 1);
+        }
+    }
+
+    starts
+}
+
+/// Which of the rows starting at `starts` the char at `col` is shown on.
+pub fn row_of(starts: &[usize], col: usize) -> usize {
+    starts.iter().rposition(|start| *start <= col).unwrap_or(0)
+}
+
+/// The start of the grapheme cluster in row `row` of `line` closest to
+/// `display_offset` columns from the row's start.
+pub fn col_in_row(
+    line: &str,
+    starts: &[usize],
+    row: usize,
+    display_offset: usize,
+    tabstop: usize,
+) -> usize {
+    let start = starts[row];
+    let next_row = starts.get(row + 1).copied();
+    let clusters = columns::clusters(line, tabstop);
+    let row_clusters: Vec<_> = clusters
+        .iter()
+        .filter(|c| c.char_col >= start && next_row.map(|n| c.char_col < n).unwrap_or(true))
+        .collect();
+    let row_col = row_clusters.first().map(|c| c.display_col).unwrap_or(0);
+
+    match row_clusters
+        .iter()
+        .find(|c| c.display_col + c.width - row_col > display_offset)
+    {
+        Some(c) => c.char_col,
+        // past the end: the last cluster of a wrapped row, or the end of
+        // the line
+        None => match (next_row, row_clusters.last()) {
+            (Some(_), Some(last)) => last.char_col,
+            _ => line.chars().count(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wraps_at_word_boundaries() {
+        assert_eq!(row_starts("short", 10, 4), vec![0]);
+        assert_eq!(row_starts("the quick brown fox", 10, 4), vec![0, 10]);
+        assert_eq!(row_starts("abcdefghijklmnop", 6, 4), vec![0, 6, 12]);
+        assert_eq!(row_starts("", 6, 4), vec![0]);
+        assert_eq!(row_starts("日本語日本語", 5, 4), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn finds_rows_and_columns() {
+        let line = "the quick brown fox";
+        let starts = row_starts(line, 10, 4);
+        assert_eq!(row_of(&starts, 4), 0);
+        assert_eq!(row_of(&starts, 12), 1);
+
+        assert_eq!(col_in_row(line, &starts, 1, 2, 4), 12);
+        assert_eq!(col_in_row(line, &starts, 1, 20, 4), line.len());
+        assert_eq!(col_in_row(line, &starts, 0, 20, 4), 9);
+
+        let wide = "日本語日本語";
+        let starts = row_starts(wide, 5, 4);
+        assert_eq!(col_in_row(wide, &starts, 1, 3, 4), 3);
+    }
+}